@@ -0,0 +1,38 @@
+use chat::utils::{Codec, ContactProtocol, HistoryEntry, MessageType, MsgStatus, NotifyProtocol, Protocols, Reply};
+use chrono::Utc;
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+
+fn text_message() -> Protocols {
+  Protocols::NPType(NotifyProtocol::MsgFromUser { msg_id: 42, sender: "alice".to_string(),
+    content: MessageType::TextMsg { content: "See you at the usual place at eight".to_string() }, time: Utc::now() })
+}
+
+/// An end-to-end encrypted message, its nonce and ciphertext travel as binary.
+fn encrypted_message() -> Protocols {
+  Protocols::NPType(NotifyProtocol::MsgFromUser { msg_id: 43, sender: "alice".to_string(),
+    content: MessageType::EncryptedMsg { sender_key: "rq:rM>}U?@Lns47E1%kR.o@n%FcmmsL/@{H8]yf7".to_string(), nonce: vec![0x5a; 24],
+      ciphertext: (0..1024).map(|i| (i * 31 % 251) as u8).collect() }, time: Utc::now() })
+}
+
+/// A full history page, the largest frame the server sends.
+fn history_page() -> Protocols {
+  let messages = (0..100).map(|msg_id| HistoryEntry { msg_id, sender: "alice".to_string(), target: "bob".to_string(),
+    content: MessageType::TextMsg { content: format!("Message number {} of the conversation", msg_id) }, time: Utc::now() }).collect();
+  Protocols::CPType(ContactProtocol::Response { req_id: 7, state: MsgStatus::ACCEPTED, reply: Reply::History { messages, total: 100 }, time: Utc::now() })
+}
+
+fn codecs(c: &mut Criterion) {
+  for (name, frame) in [("text_message", text_message()), ("encrypted_message", encrypted_message()), ("history_page", history_page())] {
+    let mut group = c.benchmark_group(name);
+    for codec in Codec::all() {
+      let raw = codec.encode(&frame).unwrap();
+      group.throughput(Throughput::Bytes(raw.len() as u64));
+      group.bench_function(format!("encode/{:?}", codec), |b| b.iter(|| codec.encode(black_box(&frame)).unwrap()));
+      group.bench_function(format!("decode/{:?}", codec), |b| b.iter(|| codec.decode(black_box(&raw)).unwrap()));
+    }
+    group.finish();
+  }
+}
+
+criterion_group!(benches, codecs);
+criterion_main!(benches);
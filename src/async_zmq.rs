@@ -0,0 +1,119 @@
+//! Async counterparts of `ZmqJsonServer` and `ZmqJsonClient`, woken by the socket's `ZMQ_FD` on a Tokio reactor.
+use std::{future::Future, io, os::fd::RawFd};
+use tokio::io::unix::AsyncFd;
+use crate::utils::{decode_frame, split_frames, ChatError, Codec, Protocols};
+
+/// Wait until `socket` has `events` pending. `ZMQ_FD` only signals that `ZMQ_EVENTS` changed and is edge
+/// triggered, so readiness is cleared before checking the events again rather than trusted on its own.
+pub(crate) async fn wait_for(fd: &AsyncFd<RawFd>, socket: &mut zmq::Socket, events: zmq::PollEvents) -> io::Result<()> {
+  loop {
+    if socket.get_events()? as zmq::PollEvents & events != 0 {
+      return Ok(());
+    }
+    let mut guard = fd.readable().await?;
+    guard.clear_ready();
+  }
+}
+
+/// A zmq socket whose sends and receives wait on the reactor instead of blocking the thread.
+pub struct AsyncSocket {
+  socket: zmq::Socket,
+  fd: AsyncFd<RawFd>,
+}
+
+impl AsyncSocket {
+  /// Must be called from within a Tokio runtime.
+  pub fn new(socket: zmq::Socket) -> io::Result<AsyncSocket> {
+    let fd = AsyncFd::new(socket.get_fd()?)?;
+    Ok(AsyncSocket { socket, fd })
+  }
+
+  pub fn get_ref(&self) -> &zmq::Socket {
+    &self.socket
+  }
+
+  pub fn into_inner(self) -> zmq::Socket {
+    self.socket
+  }
+
+  /// Wait until a message can be received.
+  pub async fn readable(&mut self) -> io::Result<()> {
+    wait_for(&self.fd, &mut self.socket, zmq::POLLIN).await
+  }
+
+  pub async fn recv_multipart(&mut self) -> io::Result<Vec<Vec<u8>>> {
+    self.retry(zmq::POLLIN, |socket| socket.recv_multipart(zmq::DONTWAIT)).await
+  }
+
+  pub async fn recv_bytes(&mut self) -> io::Result<Vec<u8>> {
+    self.retry(zmq::POLLIN, |socket| socket.recv_bytes(zmq::DONTWAIT)).await
+  }
+
+  pub async fn send_multipart(&mut self, frames: &[&[u8]]) -> io::Result<()> {
+    self.retry(zmq::POLLOUT, |socket| socket.send_multipart(frames, zmq::DONTWAIT)).await
+  }
+
+  pub async fn send(&mut self, data: &[u8]) -> io::Result<()> {
+    self.retry(zmq::POLLOUT, |socket| socket.send(data, zmq::DONTWAIT)).await
+  }
+
+  /// Run the non-blocking `op` until it stops returning EAGAIN, waiting for `events` in between.
+  async fn retry<T>(&mut self, events: zmq::PollEvents, mut op: impl FnMut(&zmq::Socket) -> zmq::Result<T>) -> io::Result<T> {
+    loop {
+      match op(&self.socket) {
+        Ok(_val) => {return Ok(_val);},
+        Err(zmq::Error::EAGAIN) => {wait_for(&self.fd, &mut self.socket, events).await?;},
+        Err(e) => {return Err(e.into());}
+      }
+    }
+  }
+}
+
+pub trait AsyncZmqJsonServer {
+  /// The sender's identity and the payload, still encoded.
+  fn recv_frame(&mut self) -> impl Future<Output = Result<(String, Vec<u8>), ChatError>> + Send;
+  fn recv_json(&mut self) -> impl Future<Output = Result<(String, Protocols), ChatError>> + Send;
+  fn send_frame(&mut self, client_id: &str, data: &Protocols, codec: Codec) -> impl Future<Output = Result<(), ChatError>> + Send;
+  fn send_json(&mut self, client_id: &str, data: &Protocols) -> impl Future<Output = Result<(), ChatError>> + Send;
+}
+
+pub trait AsyncZmqJsonClient {
+  fn recv_json(&mut self) -> impl Future<Output = Result<Protocols, ChatError>> + Send;
+  fn send_frame(&mut self, data: &Protocols, codec: Codec) -> impl Future<Output = Result<(), ChatError>> + Send;
+  fn send_json(&mut self, data: &Protocols) -> impl Future<Output = Result<(), ChatError>> + Send;
+}
+
+impl AsyncZmqJsonServer for AsyncSocket {
+  async fn recv_frame(&mut self) -> Result<(String, Vec<u8>), ChatError> {
+    let raw_msgs = self.recv_multipart().await?;
+    split_frames(raw_msgs)
+  }
+  async fn recv_json(&mut self) -> Result<(String, Protocols), ChatError> {
+    let (client_id, raw_msg) = self.recv_frame().await?;
+    Ok((client_id, decode_frame(&raw_msg)?))
+  }
+  async fn send_frame(&mut self, client_id: &str, data: &Protocols, codec: Codec) -> Result<(), ChatError> {
+    let frame = codec.encode(data)?;
+    self.send_multipart(&[client_id.as_bytes(), &frame]).await?;
+    Ok(())
+  }
+  async fn send_json(&mut self, client_id: &str, data: &Protocols) -> Result<(), ChatError> {
+    AsyncZmqJsonServer::send_frame(self, client_id, data, Codec::Json).await
+  }
+}
+
+impl AsyncZmqJsonClient for AsyncSocket {
+  async fn recv_json(&mut self) -> Result<Protocols, ChatError> {
+    let raw_msg = self.recv_bytes().await?;
+    let msg = decode_frame(&raw_msg)?;
+    Ok(msg)
+  }
+  async fn send_frame(&mut self, data: &Protocols, codec: Codec) -> Result<(), ChatError> {
+    let frame = codec.encode(data)?;
+    self.send(&frame).await?;
+    Ok(())
+  }
+  async fn send_json(&mut self, data: &Protocols) -> Result<(), ChatError> {
+    AsyncZmqJsonClient::send_frame(self, data, Codec::Json).await
+  }
+}
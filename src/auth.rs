@@ -0,0 +1,259 @@
+use argon2::{Argon2, PasswordHash, PasswordHasher, PasswordVerifier, password_hash::SaltString};
+use chrono::{DateTime, Utc};
+use log::info;
+use rand::{distributions::Alphanumeric, rngs::OsRng, Rng};
+use serde::{Deserialize, Serialize};
+use std::{collections::{BTreeMap, HashMap}, fs, path::{Path, PathBuf}};
+use crate::utils::Credentials;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub enum Role {
+  User,
+  Admin,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Ban {
+  /// Lifted automatically at this time, permanent when `None`.
+  pub until: Option<DateTime<Utc>>,
+  pub reason: Option<String>,
+}
+
+impl Ban {
+  pub fn active(&self) -> bool {
+    self.until.is_none_or(|until| Utc::now() < until)
+  }
+}
+
+impl std::fmt::Display for Ban {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self.until {
+      Some(until) => write!(f, "Banned until {}", until.format("%Y-%m-%d %H:%M:%S UTC"))?,
+      None => write!(f, "Banned permanently")?,
+    }
+    match &self.reason {
+      Some(reason) => write!(f, ": {}", reason),
+      None => Ok(()),
+    }
+  }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Account {
+  password_hash: String,
+  role: Role,
+  /// End-to-end public key the owner last published, Z85 encoded.
+  #[serde(default)]
+  public_key: Option<String>,
+  #[serde(default)]
+  ban: Option<Ban>,
+}
+
+struct Session {
+  client_id: String,
+  role: Role,
+}
+
+pub fn new_token() -> String {
+  OsRng.sample_iter(&Alphanumeric).take(32).map(char::from).collect()
+}
+
+/// Accounts with argon2 password hashes, persisted as JSON, plus the session tokens handed out since startup.
+pub struct UserDb {
+  path: PathBuf,
+  accounts: BTreeMap<String, Account>,
+  sessions: HashMap<String, Session>,
+}
+
+impl UserDb {
+  pub fn open(path: &Path) -> Result<UserDb, Box<dyn std::error::Error>> {
+    let accounts = if path.exists() {
+      serde_json::from_slice(&fs::read(path)?)?
+    } else {
+      BTreeMap::new()
+    };
+    info!("User database {} opened with {} accounts", path.display(), accounts.len());
+    Ok(UserDb { path: path.to_path_buf(), accounts, sessions: HashMap::new() })
+  }
+
+  fn save(&self) -> Result<(), String> {
+    let tmp_path = self.path.with_extension("tmp");
+    let json = serde_json::to_vec_pretty(&self.accounts).map_err(|e| e.to_string())?;
+    fs::write(&tmp_path, json).and_then(|_| fs::rename(&tmp_path, &self.path))
+      .map_err(|e| format!("Failed to save {}: {}", self.path.display(), e))
+  }
+
+  pub fn contains(&self, client_id: &str) -> bool {
+    self.accounts.contains_key(client_id)
+  }
+
+  pub fn add_user(&mut self, client_id: &str, password: &str, role: Role) -> Result<(), String> {
+    if self.accounts.contains_key(client_id) {
+      return Err(format!("Account {} already exists", client_id));
+    }
+    let salt = SaltString::generate(&mut OsRng);
+    let password_hash = Argon2::default().hash_password(password.as_bytes(), &salt)
+      .map_err(|e| format!("Failed to hash password: {}", e))?.to_string();
+    self.accounts.insert(client_id.to_string(), Account { password_hash, role, public_key: None, ban: None });
+    self.save()
+  }
+
+  pub fn remove_user(&mut self, client_id: &str) -> Result<(), String> {
+    if self.accounts.remove(client_id).is_none() {
+      return Err(format!("No account {}", client_id));
+    }
+    self.sessions.retain(|_, session| session.client_id != client_id);
+    self.save()
+  }
+
+  pub fn role(&self, client_id: &str) -> Option<Role> {
+    self.accounts.get(client_id).map(|account| account.role)
+  }
+
+  /// The account's ban, unless there is none or it has expired.
+  pub fn active_ban(&self, client_id: &str) -> Option<&Ban> {
+    self.accounts.get(client_id).and_then(|account| account.ban.as_ref()).filter(|ban| ban.active())
+  }
+
+  /// Ban the account and end its sessions.
+  pub fn ban(&mut self, client_id: &str, ban: Ban) -> Result<(), String> {
+    let account = self.accounts.get_mut(client_id).ok_or(format!("No account {}", client_id))?;
+    account.ban = Some(ban);
+    self.sessions.retain(|_, session| session.client_id != client_id);
+    self.save()
+  }
+
+  pub fn unban(&mut self, client_id: &str) -> Result<(), String> {
+    let account = self.accounts.get_mut(client_id).ok_or(format!("No account {}", client_id))?;
+    if account.ban.take().is_none() {
+      return Err(format!("{} is not banned", client_id));
+    }
+    self.save()
+  }
+
+  pub fn public_key(&self, client_id: &str) -> Option<&str> {
+    self.accounts.get(client_id).and_then(|account| account.public_key.as_deref())
+  }
+
+  pub fn set_public_key(&mut self, client_id: &str, public_key: String) -> Result<(), String> {
+    let account = self.accounts.get_mut(client_id).ok_or(format!("No account {}", client_id))?;
+    if account.public_key.as_deref() == Some(public_key.as_str()) {
+      return Ok(());
+    }
+    account.public_key = Some(public_key);
+    self.save()
+  }
+
+  /// Start a session that is not backed by an account, used for the server's own control socket.
+  pub fn grant_session(&mut self, client_id: &str, role: Role, token: String) {
+    self.sessions.insert(token, Session { client_id: client_id.to_string(), role });
+  }
+
+  /// Check the credentials presented for `client_id`. A password login returns a fresh session token
+  /// the client can present instead of the password when it registers again. `Credentials::None` never passes.
+  pub fn authenticate(&mut self, client_id: &str, credentials: &Credentials) -> Result<(Role, String), String> {
+    if let Some(ban) = self.active_ban(client_id) {
+      return Err(ban.to_string());
+    }
+    match credentials {
+      Credentials::Password(password) => {
+        let account = self.accounts.get(client_id).ok_or("Unknown account or wrong password")?;
+        let hash = PasswordHash::new(&account.password_hash).map_err(|e| format!("Corrupt password hash: {}", e))?;
+        Argon2::default().verify_password(password.as_bytes(), &hash).map_err(|_| "Unknown account or wrong password")?;
+        let role = account.role;
+        let token = new_token();
+        self.grant_session(client_id, role, token.clone());
+        Ok((role, token))
+      },
+      Credentials::Token(token) => {
+        match self.sessions.get(token) {
+          Some(session) if session.client_id == client_id => Ok((session.role, token.clone())),
+          _ => Err("Invalid or expired session token".to_string()),
+        }
+      },
+      Credentials::None => Err("Authentication required".to_string()),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use chrono::Duration;
+
+  fn db_with(dir: &Path, client_id: &str, password: &str) -> UserDb {
+    let mut db = UserDb::open(&dir.join("users.json")).unwrap();
+    db.add_user(client_id, password, Role::User).unwrap();
+    db
+  }
+
+  #[test]
+  fn password_is_verified_against_argon2_hash() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut db = db_with(dir.path(), "alice", "s3cret");
+    let saved = fs::read_to_string(dir.path().join("users.json")).unwrap();
+    assert!(!saved.contains("s3cret"));
+    assert!(saved.contains("$argon2"));
+
+    let (role, token) = db.authenticate("alice", &Credentials::Password("s3cret".to_string())).unwrap();
+    assert_eq!(role, Role::User);
+    assert_eq!(token.len(), 32);
+    // The hash survives a restart
+    let mut reopened = UserDb::open(&dir.path().join("users.json")).unwrap();
+    assert!(reopened.authenticate("alice", &Credentials::Password("s3cret".to_string())).is_ok());
+    assert!(reopened.add_user("alice", "other", Role::Admin).is_err());
+  }
+
+  #[test]
+  fn wrong_password_is_rejected() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut db = db_with(dir.path(), "alice", "s3cret");
+    let wrong = db.authenticate("alice", &Credentials::Password("S3cret".to_string())).unwrap_err();
+    let unknown = db.authenticate("mallory", &Credentials::Password("s3cret".to_string())).unwrap_err();
+    assert_eq!(wrong, "Unknown account or wrong password");
+    assert_eq!(wrong, unknown);
+    assert_eq!(db.authenticate("alice", &Credentials::None).unwrap_err(), "Authentication required");
+  }
+
+  #[test]
+  fn session_token_is_bound_to_client_and_process() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut db = db_with(dir.path(), "alice", "s3cret");
+    db.add_user("bob", "hunter2", Role::User).unwrap();
+    let (_, token) = db.authenticate("alice", &Credentials::Password("s3cret".to_string())).unwrap();
+
+    assert_eq!(db.authenticate("alice", &Credentials::Token(token.clone())).unwrap(), (Role::User, token.clone()));
+    assert_eq!(db.authenticate("bob", &Credentials::Token(token.clone())).unwrap_err(), "Invalid or expired session token");
+    assert!(db.authenticate("alice", &Credentials::Token(new_token())).is_err());
+
+    let mut reopened = UserDb::open(&dir.path().join("users.json")).unwrap();
+    assert!(reopened.authenticate("alice", &Credentials::Token(token.clone())).is_err());
+    db.remove_user("alice").unwrap();
+    assert!(db.authenticate("alice", &Credentials::Token(token)).is_err());
+  }
+
+  #[test]
+  fn ban_blocks_until_it_expires() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut db = db_with(dir.path(), "alice", "s3cret");
+    let password = Credentials::Password("s3cret".to_string());
+    let (_, token) = db.authenticate("alice", &password).unwrap();
+
+    db.ban("alice", Ban { until: Some(Utc::now() + Duration::hours(1)), reason: Some("spam".to_string()) }).unwrap();
+    let reason = db.authenticate("alice", &password).unwrap_err();
+    assert!(reason.starts_with("Banned until") && reason.ends_with(": spam"), "unexpected reason {}", reason);
+    // The ban ended the session too
+    db.unban("alice").unwrap();
+    assert!(db.authenticate("alice", &Credentials::Token(token)).is_err());
+    assert!(db.authenticate("alice", &password).is_ok());
+    assert!(db.unban("alice").is_err());
+
+    db.ban("alice", Ban { until: Some(Utc::now() - Duration::seconds(1)), reason: None }).unwrap();
+    assert!(db.active_ban("alice").is_none());
+    assert!(db.authenticate("alice", &password).is_ok());
+
+    db.ban("alice", Ban { until: None, reason: None }).unwrap();
+    assert_eq!(db.authenticate("alice", &password).unwrap_err(), "Banned permanently");
+    assert!(UserDb::open(&dir.path().join("users.json")).unwrap().active_ban("alice").is_some());
+  }
+}
@@ -0,0 +1,332 @@
+use chrono::Local;
+use std::collections::HashMap;
+use log::{debug, info, error, warn};
+use clap::Parser;
+use chat::client::{AsyncChatClient, ClientEvent};
+use chat::config::{ClientArgs, ClientConfig};
+use chat::e2e::KeyStore;
+use chat::utils::{Availability, ClientCommand, Reply, input, input_password, print_notice, NotifyProtocol, MsgStatus, MessageType};
+
+const HISTORY_PAGE_SIZE: usize = 20;
+
+/// `sent` describes the direct messages sent in this session by their id, for showing receipts.
+fn show_notification(notification: NotifyProtocol, keys: &mut KeyStore, sent: &mut HashMap<u64, String>) {
+  match notification {
+    NotifyProtocol::MsgFromUser { sender, content: MessageType::TextMsg { content }, time, .. } => {
+      print_notice(&format!("[{}] {}: {}", time.with_timezone(&Local).format("%H:%M:%S"), sender, content));
+    },
+    NotifyProtocol::MsgFromUser { sender, content, time, .. } => {
+      let text = keys.open_from(&sender, &content).unwrap_or_else(|reason| format!("{} ({})", content, reason));
+      print_notice(&format!("[{}] {} (e2e): {}", time.with_timezone(&Local).format("%H:%M:%S"), sender, text));
+    },
+    NotifyProtocol::MsgFromRoom { room, sender, content, time } => {
+      print_notice(&format!("[{}] #{} {}: {}", time.with_timezone(&Local).format("%H:%M:%S"), room, sender, content));
+    },
+    NotifyProtocol::RoomDeleted { room, by } => {
+      print_notice(&format!("Room #{} was deleted by {}", room, by));
+    },
+    NotifyProtocol::PresenceChanged { client_id, online, time } => {
+      print_notice(&format!("[{}] {} is {}", time.with_timezone(&Local).format("%H:%M:%S"), client_id, if online {"online"} else {"offline"}));
+    },
+    NotifyProtocol::StatusChanged { client_id, presence } => {
+      print_notice(&format!("[{}] {} is {}", presence.last_seen.with_timezone(&Local).format("%H:%M:%S"), client_id, presence));
+    },
+    NotifyProtocol::Receipt { msg_id, from, state, time } => {
+      let message = if state == MsgStatus::READ {sent.remove(&msg_id)} else {sent.get(&msg_id).cloned()};
+      print_notice(&format!("[{}] {} {}", time.with_timezone(&Local).format("%H:%M:%S"), state, message.unwrap_or(format!("#{} to {}", msg_id, from))));
+    },
+    NotifyProtocol::Broadcast { sender, content, time } => {
+      print_notice(&format!("[{}] Broadcast from {}: {}", time.with_timezone(&Local).format("%H:%M:%S"), sender, content));
+    },
+    NotifyProtocol::Kicked { reason } => {
+      print_notice(&format!("Disconnected by server: {}", reason));
+    },
+    NotifyProtocol::ServerShutdown { reason, grace_secs, time } => {
+      let reason = reason.map(|reason| format!(": {}", reason)).unwrap_or_default();
+      print_notice(&format!("[{}] Server shutting down in {}s{}", time.with_timezone(&Local).format("%H:%M:%S"), grace_secs, reason));
+    },
+  }
+}
+
+/// Show an event and acknowledge a direct message once shown.
+fn show_event(client: &AsyncChatClient, event: ClientEvent, keys: &mut KeyStore, sent: &mut HashMap<u64, String>, read_receipts: bool) {
+  match event {
+    ClientEvent::Notification(notification) => {
+      let read_msg_id = match notification {
+        NotifyProtocol::MsgFromUser { msg_id, .. } if msg_id != 0 && read_receipts => Some(msg_id),
+        _ => None,
+      };
+      show_notification(notification, keys, sent);
+      if let Some(msg_id) = read_msg_id {
+        client.mark_read(msg_id);
+      }
+    },
+    ClientEvent::ConnectionLost => {print_notice("Connection to server lost, reconnecting...");},
+    ClientEvent::Reconnected => {print_notice("Reconnected to server");},
+  }
+}
+
+fn main() {
+  let config = match ClientConfig::load(&ClientArgs::parse()) {
+    Ok(_val) => _val,
+    Err(e) => {eprintln!("{}", e);std::process::exit(2);}
+  };
+  env_logger::Builder::new().parse_filters(&config.log_level).init();
+  let client_id = match &config.client_id {
+    Some(client_id) => client_id.clone(),
+    None => input("Enter client_id: "),
+  };
+  println!("Your client_id: {}", client_id);
+  let password = input_password("Password: ");
+  if config.curve.server_key.is_none() {
+    println!("Warning: CURVE is disabled, your password is sent to {} in cleartext", config.connect);
+  }
+  let runtime = match tokio::runtime::Builder::new_current_thread().enable_all().build() {
+    Ok(_val) => _val,
+    Err(e) => {error!("Failed to start runtime: {}", e);return;}
+  };
+  runtime.block_on(shell(config, client_id, password));
+  // A stdin read may still be pending, don't wait for it
+  runtime.shutdown_background();
+  info!("Total exiting...");
+}
+
+/// Read commands while showing events, both on the runtime thread the client's task runs on.
+async fn shell(config: ClientConfig, client_id: String, password: String) {
+  let mut keys = match KeyStore::open(&config.e2e_dir, &client_id) {
+    Ok(_val) => _val,
+    Err(e) => {error!("Failed to open end-to-end key store in {}: {}", config.e2e_dir.display(), e);return;}
+  };
+  let mut client = match AsyncChatClient::connect(&config, &client_id, &password).await {
+    Ok(_val) => _val,
+    Err(e) => {error!("Failed to join {}: {}", config.connect, e);return;}
+  };
+  let mut sent: HashMap<u64, String> = HashMap::new();
+  let mut events = client.subscribe();
+  match client.publish_key(keys.public_key()).await {
+    Ok(_) => {debug!("Public key published");},
+    Err(reason) => {warn!("Publish public key {}, others cannot esend to you", reason);}
+  }
+  info!("Shell ok");
+  loop {
+    let mut line = tokio::task::spawn_blocking(|| input("Enter command: "));
+    let user_input = loop {
+      tokio::select! {
+        read = &mut line => {break read.unwrap_or_default();},
+        Some(event) = events.recv() => {show_event(&client, event, &mut keys, &mut sent, config.read_receipts);},
+      }
+    };
+    if !client.is_running() {
+      println!("Disconnected by server, quiting...");
+      break;
+    }
+    let mut cmd_it = user_input.split_whitespace();
+    let cmd_type = match cmd_it.next() {
+      Some(_val) => _val,
+      None => {
+        warn!("No cmd given, try again");
+        continue;
+      }
+    };
+    match cmd_type {
+      "q" => {
+        info!("Shutdown cmd received, quiting...");
+        break;
+      },
+      "send" | "esend" => {
+        let target = match cmd_it.next() {
+          Some(_val) => _val.to_string(),
+          None => {
+            warn!("{} needs a target and a message", cmd_type);
+            continue;
+          }
+        };
+        let text = cmd_it.collect::<Vec<&str>>().join(" ");
+        if text.is_empty(){
+          warn!("Empty message, nothing sent");
+          continue;
+        }
+        let (label, content) = if cmd_type == "send" {
+          ("Message", MessageType::TextMsg { content: text })
+        } else {
+          let pinned = keys.peer_key(&target).map(str::to_string);
+          let peer_key = match pinned {
+            Some(_val) => _val,
+            None => {
+              let pinned = client.fetch_key(&target).await.and_then(|key|{keys.pin(&target, &key)?;Ok(key)});
+              match pinned {
+                Ok(_val) => {println!("Pinned key of {}: {}", target, _val);_val},
+                Err(reason) => {println!("Cannot encrypt for {}: {}", target, reason);continue;}
+              }
+            }
+          };
+          match keys.encrypt(&peer_key, &text) {
+            Ok(_val) => ("Encrypted message", _val),
+            Err(reason) => {error!("{}", reason);continue;}
+          }
+        };
+        let summary = format!("to {}: {}", target, content);
+        match client.send_message(&target, content).await {
+          Ok(reply @ (Reply::Queued | Reply::Sent { queued: true, .. })) => {
+            if let Reply::Sent { msg_id, .. } = reply {
+              sent.insert(msg_id, summary);
+            }
+            println!("{} to {} ACCEPTED, {}", label, target, reply);
+          },
+          Ok(reply) => {
+            if let Reply::Sent { msg_id, .. } = reply {
+              sent.insert(msg_id, summary);
+            }
+            println!("{} to {} ACCEPTED", label, target);
+          },
+          Err(reason) => {println!("{} to {} {}", label, target, reason);}
+        }
+      },
+      "trust" => {
+        let Some(peer) = cmd_it.next() else {
+          warn!("Usage: trust <client_id>");
+          continue;
+        };
+        match client.fetch_key(peer).await.and_then(|key|{keys.replace(peer, &key)?;Ok(key)}) {
+          Ok(key) => {println!("Now trusting {} with key {}", peer, key);},
+          Err(reason) => {println!("trust {}: {}", peer, reason);}
+        }
+      },
+      "watch" | "unwatch" => {
+        let Some(watched) = cmd_it.next() else {
+          warn!("Usage: {} <client_id>", cmd_type);
+          continue;
+        };
+        let command = if cmd_type == "watch" {ClientCommand::Watch { client_id: watched.to_string() }} else {ClientCommand::Unwatch { client_id: watched.to_string() }};
+        match client.command(command).await {
+          Ok(_) => {println!("ok");},
+          Err(reason) => {println!("{} {} {}", cmd_type, watched, reason);}
+        }
+      },
+      "status" => {
+        let availability = match cmd_it.next().map(str::parse::<Availability>) {
+          Some(Ok(_val)) => _val,
+          Some(Err(reason)) => {warn!("{}", reason);continue;},
+          None => {
+            warn!("Usage: status <online|away|busy|offline> [text]");
+            continue;
+          }
+        };
+        let text = cmd_it.collect::<Vec<&str>>().join(" ");
+        let status = if text.is_empty() {None} else {Some(text)};
+        match client.set_presence(availability, status).await {
+          Ok(_) => {println!("ok");},
+          Err(reason) => {println!("status {}", reason);}
+        }
+      },
+      "list" => {
+        match client.list_clients().await {
+          Ok(clients) => {
+            for client in clients {
+              println!("{:<16} {:<32} last seen {}", client.client_id, client.presence.to_string(), client.presence.last_seen.with_timezone(&Local).format("%Y-%m-%d %H:%M:%S"));
+            }
+          },
+          Err(reason) => {println!("List clients {}", reason);}
+        }
+      },
+      "room" => {
+        let (action, room) = (cmd_it.next(), cmd_it.next().map(|room| room.to_string()));
+        let command = match (action, room) {
+          (Some("list"), _) => ClientCommand::ListRooms,
+          (Some("create"), Some(room)) => ClientCommand::CreateRoom { room },
+          (Some("join"), Some(room)) => ClientCommand::JoinRoom { room },
+          (Some("leave"), Some(room)) => ClientCommand::LeaveRoom { room },
+          (Some("delete"), Some(room)) => ClientCommand::DeleteRoom { room },
+          (Some("members"), Some(room)) => ClientCommand::RoomMembers { room },
+          _ => {
+            warn!("Usage: room list | room <create|join|leave|delete|members> <name>");
+            continue;
+          }
+        };
+        match client.command(command).await {
+          Ok(Reply::Done) => {println!("ok");},
+          Ok(reply) => {println!("{}", reply);},
+          Err(reason) => {println!("room {}", reason);}
+        }
+      },
+      "say" => {
+        let room = match cmd_it.next() {
+          Some(_val) => _val.to_string(),
+          None => {
+            warn!("say needs a room and a message");
+            continue;
+          }
+        };
+        let text = cmd_it.collect::<Vec<&str>>().join(" ");
+        if text.is_empty(){
+          warn!("Empty message, nothing sent");
+          continue;
+        }
+        if let Err(reason) = client.send_room(&room, MessageType::TextMsg { content: text }).await {
+          println!("Message to #{} {}", room, reason);
+        }
+      },
+      "history" => {
+        let mut peer = None;
+        let mut page = 1;
+        for arg in cmd_it {
+          match arg.parse::<usize>() {
+            Ok(_val) if _val > 0 => {page = _val;},
+            _ => {peer = Some(arg.to_string());}
+          }
+        }
+        match client.history(peer, (page - 1) * HISTORY_PAGE_SIZE, HISTORY_PAGE_SIZE).await {
+          Ok((messages, total)) => {
+            let pages = total.div_ceil(HISTORY_PAGE_SIZE).max(1);
+            println!("History page {}/{} ({} messages)", page, pages, total);
+            for entry in messages.iter().rev() {
+              let text = match &entry.content {
+                MessageType::EncryptedMsg { sender_key, nonce, ciphertext } => {
+                  let peer = if entry.sender == client_id {&entry.target} else {&entry.sender};
+                  match keys.peer_key(peer) {
+                    Some(pinned) if entry.sender != client_id && pinned != sender_key => {
+                      format!("{} (key mismatch: sent with {}, pinned {})", entry.content, sender_key, pinned)
+                    },
+                    Some(pinned) => {
+                      keys.decrypt_with(pinned, nonce, ciphertext)
+                        .unwrap_or_else(|reason| format!("{} ({})", entry.content, reason))
+                    },
+                    None => format!("{} (unknown key)", entry.content),
+                  }
+                },
+                content => content.to_string(),
+              };
+              println!("[{}] {} -> {}: {}", entry.time.with_timezone(&Local).format("%Y-%m-%d %H:%M:%S"), entry.sender, entry.target, text);
+            }
+          },
+          Err(reason) => {
+            println!("History {}", reason);
+          }
+        }
+      },
+      "call" => {
+        let Some(name) = cmd_it.next() else {
+          warn!("Usage: call <command> [json args]");
+          continue;
+        };
+        let args = cmd_it.collect::<Vec<&str>>().join(" ");
+        let args = if args.is_empty() {Ok(serde_json::Value::Null)} else {serde_json::from_str(&args)};
+        match args {
+          Ok(args) => {
+            match client.call(name, args).await {
+              Ok(reply) => {println!("{}: {}", name, reply);},
+              Err(reason) => {println!("{} {}", name, reason);}
+            }
+          },
+          Err(e) => {warn!("Invalid json args: {}", e);}
+        }
+      },
+      _ => {
+        warn!("Unknow cmd");
+        continue;
+      }
+    }
+  }
+  info!("Shell done, waiting DEALER task...");
+  client.close().await;
+}
@@ -0,0 +1,270 @@
+use chrono::Utc;
+use log::{debug, error, info, warn};
+use chat::{config, curve, utils};
+use chat::async_zmq::AsyncSocket;
+use chat::server::ChatServer;
+use clap::Parser;
+use config::{ServerArgs, ServerConfig, ServerSubcommand};
+use curve::CurveKeys;
+use std::time::Duration;
+use utils::{ContactProtocol, ClientCommand, ServerCommand, Credentials, Handshake, input, parse_duration, encode_frame, decode_frame, MsgStatus, Reply, Protocols};
+
+/// How long the shell waits for the server to answer a command.
+const CONTROL_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The shell's DEALER, registered as `root`. Requests are numbered so a late or unexpected frame is never taken for the answer.
+struct ControlSocket {
+  socket: AsyncSocket,
+  next_req_id: u64,
+  /// Token of the `root` registration, sent with every request.
+  session: Option<String>,
+}
+
+impl ControlSocket {
+  async fn request(&mut self, mut control_msg: ContactProtocol) -> Option<(MsgStatus, Reply)> {
+    self.next_req_id += 1;
+    let req_id = self.next_req_id;
+    control_msg.set_req_id(req_id);
+    if let Some(session) = &self.session {
+      control_msg.set_session(session);
+    }
+    let protocol_msg = Protocols::CPType(control_msg);
+    if let Err(e) = self.socket.send(&encode_frame(&protocol_msg).unwrap()).await {
+      error!("Failed to send control request: {}", e);
+      return None;
+    }
+    let deadline = tokio::time::Instant::now() + CONTROL_TIMEOUT;
+    loop {
+      let raw_msg = match tokio::time::timeout_at(deadline, self.socket.recv_bytes()).await {
+        Ok(Ok(_val)) => _val,
+        Ok(Err(e)) => {error!("Failed to receive control response: {}", e);return None;},
+        Err(_) => {error!("No response from server within {:?}", CONTROL_TIMEOUT);return None;}
+      };
+      match decode_frame(&raw_msg) {
+        Ok(Protocols::CPType(ContactProtocol::Response { req_id: resp_id, state, reply, .. })) if resp_id == req_id => {return Some((state, reply));},
+        Ok(Protocols::CPType(ContactProtocol::Response { req_id: resp_id, .. })) => {debug!("Skip stale response {} on control socket", resp_id);},
+        Ok(_) => {debug!("Skip notification on control socket");},
+        Err(e) => {warn!("Failed to decode control frame: {}", e);}
+      }
+    }
+  }
+}
+
+fn main(){
+  let args = ServerArgs::parse();
+  let config = match ServerConfig::load(&args) {
+    Ok(_val) => _val,
+    Err(e) => {eprintln!("{}", e);std::process::exit(2);}
+  };
+  env_logger::Builder::new().parse_filters(&config.log_level).init();
+  if let Some(ServerSubcommand::Keygen { path }) = args.command {
+    match CurveKeys::generate().and_then(|keys| keys.save(&path).map(|_| keys)) {
+      Ok(keys) => {println!("Keypair written to {}, public key: {}", path.display(), keys.public_key);},
+      Err(e) => {error!("Failed to generate keypair: {}", e);}
+    }
+    return;
+  }
+  let control_endpoint = config.control_endpoint();
+  let heartbeat_interval = Duration::from_secs((config.client_timeout_secs / 3).max(1));
+  let server = match ChatServer::new(config) {
+    Ok(_val) => _val,
+    Err(e) => {error!("{}", e);return;}
+  };
+  let curve_keys = server.curve_keys().cloned();
+  let root_token = server.root_token().to_string();
+  let runtime = match tokio::runtime::Builder::new_current_thread().enable_all().build() {
+    Ok(_val) => _val,
+    Err(e) => {error!("Failed to start runtime: {}", e);return;}
+  };
+  runtime.block_on(async {
+    // Password checks block the thread serving, keep them away from the shell
+    let mut serving = tokio::task::spawn_blocking(move ||{
+      let server_runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().map_err(|e| e.to_string())?;
+      server_runtime.block_on(server.serve()).map_err(|e| e.to_string())
+    });
+    tokio::select! {
+      served = &mut serving => {
+        match served {
+          Ok(Ok(_)) => {info!("Server stopped");},
+          Ok(Err(e)) => {error!("Server err detected: {}, exiting...", e);},
+          Err(e) => {error!("Server thread failed: {}, exiting...", e);}
+        }
+        return;
+      },
+      stopping = shell(&control_endpoint, curve_keys.as_ref(), root_token, heartbeat_interval) => {
+        if !stopping {
+          return;
+        }
+      },
+    }
+    match serving.await {
+      Ok(Ok(_)) => {info!("Server stopped");},
+      Ok(Err(e)) => {error!("Server err detected: {}", e);},
+      Err(e) => {error!("Server thread failed: {}", e);}
+    }
+  });
+  // A stdin read may still be pending, don't wait for it
+  runtime.shutdown_background();
+  info!("Total exiting...");
+}
+
+/// Wait for the next line on stdin, keeping the control socket's registration alive meanwhile.
+async fn read_command(control: &mut ControlSocket, heartbeat_interval: Duration) -> String {
+  let mut reading = tokio::task::spawn_blocking(|| input("Enter command: "));
+  loop {
+    tokio::select! {
+      line = &mut reading => {return line.unwrap_or_default();},
+      _ = tokio::time::sleep(heartbeat_interval) => {
+        let heartbeat_msg = ContactProtocol::ClientControl { req_id: 0, session: None, state: MsgStatus::SUBMITTED, command: ClientCommand::Heartbeat, time: Utc::now() };
+        if let Some((state, reply)) = control.request(heartbeat_msg).await.filter(|(state, _)| *state != MsgStatus::ACCEPTED) {
+          warn!("Control socket heartbeat {}: {}", state, reply);
+        }
+      },
+    }
+  }
+}
+
+/// Serve the admin commands typed on stdin through a control socket registered as `root`.
+/// True once the server accepted to shut down, false when the shell could not start.
+async fn shell(control_endpoint: &str, curve_keys: Option<&CurveKeys>, root_token: String, heartbeat_interval: Duration) -> bool {
+  let zmq_ctx = zmq::Context::new();
+  let control_socket = zmq_ctx.socket(zmq::DEALER).unwrap();
+  control_socket.set_identity("root".as_bytes()).unwrap();
+  if let Some(keys) = curve_keys {
+    keys.apply_client(&control_socket, &keys.public_key).unwrap();
+  }
+  if let Err(e) = control_socket.connect(control_endpoint) {
+    error!("Failed to connect control socket to {}: {}", control_endpoint, e);
+    return false;
+  }
+  let mut control = match AsyncSocket::new(control_socket) {
+    Ok(socket) => ControlSocket { socket, next_req_id: 0, session: None },
+    Err(e) => {error!("Failed to watch control socket: {}", e);return false;}
+  };
+  // No capabilities: nobody can push messages, receipts or notices onto the control socket.
+  // It still sends heartbeats while waiting for input, so its registration is not evicted as idle
+  let mut control_handshake = Handshake::current();
+  control_handshake.capabilities.clear();
+  let register_msg = ContactProtocol::ClientControl { req_id: 0, session: None, state: MsgStatus::SUBMITTED,
+    command: ClientCommand::Register { handshake: control_handshake, credentials: Credentials::Token(root_token) }, time: Utc::now() };
+  match control.request(register_msg).await {
+    Some((MsgStatus::ACCEPTED, Reply::Registered { token, .. })) => {
+      debug!("Control socket registered");
+      control.session = Some(token);
+    },
+    Some((state, reply)) => {error!("Control socket register {}: {}", state, reply);return false;},
+    None => {return false;}
+  }
+  info!("Shell ok");
+  loop {
+    let user_input = read_command(&mut control, heartbeat_interval).await;
+    let mut cmd_it = user_input.split_whitespace();
+    let cmd_type = match cmd_it.next() {
+      Some(_val) => _val,
+      None => {
+        warn!("No cmd given, try again");
+        continue;
+      }
+    };
+    match cmd_type {
+      "q" => {
+        let mut rest: Vec<&str> = cmd_it.collect();
+        let grace_secs = match rest.first().map(|arg| parse_duration(arg)) {
+          Some(Ok(grace)) => {rest.remove(0);grace.num_seconds() as u64},
+          _ => 0,
+        };
+        let reason = Some(rest.join(" ")).filter(|reason| !reason.is_empty());
+        let quit_msg = ContactProtocol::ServerControl { req_id: 0, session: None, state: MsgStatus::SUBMITTED, command: ServerCommand::Shutdown { reason, grace_secs }, time: Utc::now() };
+        match control.request(quit_msg).await {
+          Some((MsgStatus::ACCEPTED, _)) => {
+            println!("Stopping in {}s", grace_secs);
+            // Leave the registration behind for nobody while the server finishes its grace period
+            let unregister_msg = ContactProtocol::ClientControl { req_id: 0, session: None, state: MsgStatus::SUBMITTED, command: ClientCommand::Unregister, time: Utc::now() };
+            control.request(unregister_msg).await;
+            return true;
+          },
+          Some((state, reply)) => {println!("shutdown {}: {}", state, reply);},
+          None => {},
+        }
+      },
+      "client" => {
+        match cmd_it.next() {
+          Some(val) => {
+            match val {
+              "list" => {
+                let client_list_msg = 
+                  ContactProtocol::ClientControl { req_id: 0, session: None, state: MsgStatus::SUBMITTED, command: ClientCommand::ListClients, time: Utc::now() };
+                if let Some((_, reply)) = control.request(client_list_msg).await {
+                  println!("clients: {}", reply);
+                }
+              },
+              "kick" | "ban" | "unban" | "info" => {
+                let Some(client_id) = cmd_it.next().map(str::to_string) else {
+                  warn!("Usage: client kick <id> [reason] | client ban <id> [duration] [reason] | client unban <id> | client info <id>");
+                  continue;
+                };
+                let mut rest: Vec<&str> = cmd_it.collect();
+                let command = match val {
+                  "kick" => ServerCommand::Kick { client_id, reason: Some(rest.join(" ")).filter(|reason| !reason.is_empty()) },
+                  "ban" => {
+                    let until = match rest.first().map(|arg| parse_duration(arg)) {
+                      Some(Ok(duration)) => {rest.remove(0);Some(Utc::now() + duration)},
+                      _ => None,
+                    };
+                    ServerCommand::Ban { client_id, until, reason: Some(rest.join(" ")).filter(|reason| !reason.is_empty()) }
+                  },
+                  "unban" => ServerCommand::Unban { client_id },
+                  _ => ServerCommand::Info { client_id },
+                };
+                let client_msg = ContactProtocol::ServerControl { req_id: 0, session: None, state: MsgStatus::SUBMITTED, command, time: Utc::now() };
+                if let Some((state, reply)) = control.request(client_msg).await {
+                  println!("client {}: {}", state, reply);
+                }
+              },
+              _ => {
+                warn!("Invalid clint arg: {}", val);
+                continue;
+              }
+            }
+          },
+          None => {
+            warn!("client needs args");
+            continue;
+          }
+        }
+      }
+      "user" => {
+        let command = match (cmd_it.next(), cmd_it.next(), cmd_it.next(), cmd_it.next()) {
+          (Some("add"), Some(client_id), Some(password), role) => {
+            ServerCommand::AddUser { client_id: client_id.to_string(), password: Credentials::Password(password.to_string()), admin: role == Some("admin") }
+          },
+          (Some("del"), Some(client_id), None, None) => ServerCommand::RemoveUser { client_id: client_id.to_string() },
+          _ => {
+            warn!("Usage: user add <id> <password> [admin] | user del <id>");
+            continue;
+          }
+        };
+        let user_msg = ContactProtocol::ServerControl { req_id: 0, session: None, state: MsgStatus::SUBMITTED, command, time: Utc::now() };
+        if let Some((state, reply)) = control.request(user_msg).await {
+          println!("user {}: {}", state, reply);
+        }
+      },
+      "broadcast" => {
+        let content = cmd_it.collect::<Vec<&str>>().join(" ");
+        if content.is_empty() {
+          warn!("Usage: broadcast <text>");
+          continue;
+        }
+        let broadcast_msg = ContactProtocol::ServerControl { req_id: 0, session: None, state: MsgStatus::SUBMITTED, command: ServerCommand::Broadcast { content }, time: Utc::now() };
+        if let Some((state, reply)) = control.request(broadcast_msg).await {
+          println!("broadcast {}: {}", state, reply);
+        }
+      },
+      _ => {
+        warn!("Unknow cmd");
+        continue;
+      }
+    }
+  }
+}
+
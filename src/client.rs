@@ -1,177 +1,906 @@
 use chrono::Utc;
-use zmq;
-use std::sync::{mpsc, Arc, OnceLock, atomic::{AtomicU8, Ordering}};
-use serde::{Serialize, Deserialize};
-use log::{debug, info, error, warn};
-mod utils;
-use utils::{ZmqJsonClient, ContactProtocol, input, NotifyProtocol, MsgStatus, MessageType, Protocols};
-
-static CLIENT_ID: OnceLock<String> = OnceLock::new();
-
-impl ZmqJsonClient for zmq::Socket {
-  fn recv_json<Protocols: for<'a> Deserialize<'a>>(&self, flags: Option<i32>) -> Result<Protocols, Box<dyn std::error::Error>> {
-    let raw_msg = self.recv_bytes(flags.unwrap_or(0))?;
-    let msg = serde_json::from_slice(&raw_msg)?;
-    Ok(msg)
-  }
-  fn send_json<Protocols: Serialize>(&self, data: &Protocols, flags: Option<i32>) -> Result<(), Box<dyn std::error::Error>> {
-    let json_bytes = serde_json::to_vec(data)?;
-    self.send(&json_bytes, flags.unwrap_or(0))?;
-    Ok(())
-  }
-}
-
-fn main() {
-  env_logger::init();
-  CLIENT_ID.set(input("Enter client_id: ")).unwrap();
-  println!("Your client_id: {}", CLIENT_ID.get().unwrap());
-  let zmq_ctx = Arc::new(zmq::Context::new());
-  let ctx_for_dealer = Arc::clone(&zmq_ctx);
-  let (main_sender, thread_receiver) = mpsc::channel();
-  let thread_state = Arc::new(AtomicU8::new(0));
-  let thread_state_clone = thread_state.clone();
-  let dealer_handle = std::thread::spawn(move ||{
-    debug!("Child thread with DEALER start");
-    let socket;
-    let thread_err = |err_msg: String, exit_code: u8|{error!("{}", err_msg);thread_state_clone.store(exit_code, Ordering::Relaxed);};
-    match ctx_for_dealer.socket(zmq::DEALER) {
-      Ok(_val) => {socket = _val;debug!("DEALER socket created");},
-      Err(e) => {thread_err(format!("Failed to create socket: {}", e.to_string()), 9);return;}
-    }
-    match socket.set_identity(CLIENT_ID.get().unwrap().as_bytes()) {
-      Ok(_) => {debug!("Identity set");},
-      Err(e) => {thread_err(format!("Failed to set Identity due to {}", e.to_string()), 10);return;}
-    }
-    match socket.connect("tcp://39.105.24.101:23") {
-      Ok(_val) => {debug!("Connect ok");},
-      Err(e) => {thread_err(format!("Failed to bind to 5555: {}", e.to_string()), 2);return;}
-    }
-    match socket.set_rcvtimeo(5000) {
-      Ok(_) => {debug!("Set init recv timeout ok");},
-      Err(e) => {thread_err(format!("Failed to set init recv timeout: {}", e.to_string()), 3);return;}
-    }
-    info!("Socket connected, try to register client");
-    let register_msg = 
-      Protocols::CPType(ContactProtocol::ClientControl { state: MsgStatus::SUBMITTED, command: "register".to_string(), cmd_args: None, time: Utc::now() });
-    match socket.send_json(&register_msg, Some(0)) {
-      Ok(_val) => {debug!("Register request sent");},
-      Err(e) => {thread_err(format!("Failed send register request: {}", e.to_string()),4);return;}
+use log::{debug, error, info, warn};
+use std::{collections::HashMap, sync::{mpsc, Arc, Mutex}, thread::JoinHandle, time::{Duration, Instant}};
+use crate::{config::ClientConfig, curve::CurveKeys};
+#[cfg(feature = "async")]
+use crate::async_zmq::{AsyncSocket, AsyncZmqJsonClient};
+use crate::utils::{ZmqJsonClient, Availability, ChatError, Codec, ClientCommand, ClientPresence, ContactProtocol, Credentials, Handshake, HistoryEntry, MessageType, MsgStatus, NotifyProtocol, Protocols, Reply};
+
+const REGISTER_REQ_ID: u64 = 1;
+/// Heartbeats and read receipts all share this id so their acknowledgements are never mistaken for a command's response.
+const UNTRACKED_REQ_ID: u64 = u64::MAX;
+/// Silence from the server for this many heartbeat intervals means the connection is lost.
+const MISSED_HEARTBEATS: u32 = 3;
+const RECONNECT_MIN_BACKOFF: Duration = Duration::from_secs(1);
+
+enum DealerCmd {
+  Shutdown,
+  Request{msg: ContactProtocol, reply: Responder, timeout: Duration},
+  /// Fire and forget, nobody waits for the acknowledgement.
+  Send{msg: ContactProtocol},
+}
+
+/// Where the response to a request goes, depending on whether the caller blocks or awaits.
+enum Responder {
+  Blocking(mpsc::Sender<ContactProtocol>),
+  #[cfg(feature = "async")]
+  Async(tokio::sync::oneshot::Sender<ContactProtocol>),
+}
+
+impl Responder {
+  /// False when the caller stopped waiting.
+  fn send(self, response: ContactProtocol) -> bool {
+    match self {
+      Responder::Blocking(sender) => sender.send(response).is_ok(),
+      #[cfg(feature = "async")]
+      Responder::Async(sender) => sender.send(response).is_ok(),
+    }
+  }
+}
+
+enum Subscriber {
+  Blocking(mpsc::Sender<ClientEvent>),
+  #[cfg(feature = "async")]
+  Async(tokio::sync::mpsc::UnboundedSender<ClientEvent>),
+}
+
+impl Subscriber {
+  /// False once the receiving end is gone.
+  fn send(&self, event: ClientEvent) -> bool {
+    match self {
+      Subscriber::Blocking(sender) => sender.send(event).is_ok(),
+      #[cfg(feature = "async")]
+      Subscriber::Async(sender) => sender.send(event).is_ok(),
     }
+  }
+}
+
+/// What subscribers of a `ChatClient` receive.
+#[derive(Clone)]
+pub enum ClientEvent {
+  Notification(NotifyProtocol),
+  /// The server went quiet or announced its shutdown, requests are held until it is back.
+  ConnectionLost,
+  Reconnected,
+}
+
+/// A request sent but not answered yet. It is kept to resend after reconnecting,
+/// so a request whose response got lost with the connection may be delivered twice.
+struct PendingRequest {
+  msg: Protocols,
+  reply: Responder,
+  deadline: Instant,
+}
+
+fn failed_response(req_id: u64, reason: String) -> ContactProtocol {
+  ContactProtocol::Response { req_id, state: MsgStatus::FAILED, reply: Reply::Reason(reason), time: Utc::now() }
+}
+
+/// The reply of an ACCEPTED response, anything else as `Err` with its state and reason.
+fn response_reply(response: ContactProtocol) -> Result<Reply, String> {
+  match response {
+    ContactProtocol::Response { state: MsgStatus::ACCEPTED, reply, .. } => Ok(reply),
+    ContactProtocol::Response { state, reply, .. } => Err(format!("{}: {}", state, reply)),
+    _ => Err("Unexpected response".to_string()),
+  }
+}
+
+fn client_control(command: ClientCommand) -> ContactProtocol {
+  ContactProtocol::ClientControl { req_id: 0, session: None, state: MsgStatus::SUBMITTED, command, time: Utc::now() }
+}
+
+fn unexpected<T>(reply: Reply) -> Result<T, String> {
+  Err(format!("Unexpected reply: {}", reply))
+}
+
+/// A request of the client API and how to read its ACCEPTED reply, shared by `ChatClient` and `AsyncChatClient`
+/// which only differ in how they wait for the response.
+struct Call<T> {
+  msg: ContactProtocol,
+  read: fn(Reply) -> Result<T, String>,
+}
+
+impl Call<Reply> {
+  fn command(command: ClientCommand) -> Call<Reply> {
+    Call { msg: client_control(command), read: Ok }
+  }
+
+  fn custom(name: &str, args: serde_json::Value) -> Call<Reply> {
+    Call::command(ClientCommand::Custom { name: name.to_string(), args })
+  }
+
+  fn send_message(target: &str, content: MessageType) -> Call<Reply> {
+    Call { msg: ContactProtocol::User2UserMsg { req_id: 0, session: None, state: MsgStatus::SUBMITTED, target: target.to_string(), content, time: Utc::now() }, read: Ok }
+  }
+
+  fn send_room(room: &str, content: MessageType) -> Call<Reply> {
+    Call { msg: ContactProtocol::RoomMsg { req_id: 0, session: None, state: MsgStatus::SUBMITTED, room: room.to_string(), content, time: Utc::now() }, read: Ok }
+  }
+}
+
+impl Call<()> {
+  fn done(command: ClientCommand) -> Call<()> {
+    Call { msg: client_control(command), read: |_| Ok(()) }
+  }
+}
+
+impl Call<Vec<ClientPresence>> {
+  fn list_clients() -> Call<Vec<ClientPresence>> {
+    Call { msg: client_control(ClientCommand::ListClients), read: |reply| match reply {
+      Reply::Presences(clients) => Ok(clients),
+      reply => unexpected(reply),
+    }}
+  }
+}
+
+impl Call<(Vec<HistoryEntry>, usize)> {
+  fn history(peer: Option<String>, offset: usize, limit: usize) -> Call<(Vec<HistoryEntry>, usize)> {
+    Call { msg: client_control(ClientCommand::History { peer, offset, limit }), read: |reply| match reply {
+      Reply::History { messages, total } => Ok((messages, total)),
+      reply => unexpected(reply),
+    }}
+  }
+}
+
+impl Call<String> {
+  fn fetch_key(client_id: &str) -> Call<String> {
+    Call { msg: client_control(ClientCommand::FetchKey { client_id: client_id.to_string() }), read: |reply| match reply {
+      Reply::PublicKey { public_key, .. } => Ok(public_key),
+      reply => unexpected(reply),
+    }}
+  }
+}
+
+#[derive(Debug)]
+pub enum RegisterError {
+  /// The server answered and refused the credentials or handshake.
+  Rejected(String),
+  /// No answer in time, or the socket failed.
+  Failed(String),
+}
+
+impl std::fmt::Display for RegisterError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      RegisterError::Rejected(reason) => write!(f, "rejected: {}", reason),
+      RegisterError::Failed(reason) => write!(f, "{}", reason),
+    }
+  }
+}
+
+impl std::error::Error for RegisterError {}
+
+/// Always sent in JSON, the server only learns the codec from its handshake.
+fn register_msg(codec: Codec, credentials: Credentials) -> Protocols {
+  Protocols::CPType(ContactProtocol::ClientControl { req_id: REGISTER_REQ_ID, session: None, state: MsgStatus::SUBMITTED, command: ClientCommand::Register { handshake: Handshake::preferring(codec), credentials }, time: Utc::now() })
+}
+
+fn registered(state: MsgStatus, reply: Reply) -> Result<(Handshake, String), RegisterError> {
+  match (state, reply) {
+    (MsgStatus::ACCEPTED, Reply::Registered { handshake, token }) => Ok((handshake, token)),
+    (state, reply) => Err(RegisterError::Rejected(format!("server returns {}: {}", state, reply))),
+  }
+}
+
+/// Send `register` and wait up to `timeout` for its response, skipping frames left over from an earlier connection.
+fn register(socket: &zmq::Socket, codec: Codec, credentials: Credentials, timeout: Duration) -> Result<(Handshake, String), RegisterError> {
+  socket.send_json(&register_msg(codec, credentials), Some(0)).map_err(|e| RegisterError::Failed(format!("Failed send register request: {}", e)))?;
+  let deadline = Instant::now() + timeout;
+  while Instant::now() < deadline {
     match socket.recv_json(Some(0)) {
-      Ok(val) => {
-        if let Protocols::CPType(val2) = val{
-          match val2 {
-            ContactProtocol::ClientControl { state, command, cmd_args, time } => {
-              if state != MsgStatus::ACCEPTED{
-                thread_err(format!("Register request failed, server returns {}, echo is {}", state, command), 5);
-                return;
-              }
-              info!("Register successfully");
-            },
-            _ =>{
-              error!("Failed deserialize register result, not other protocol");
-              thread_state_clone.store(6, Ordering::Relaxed);
-              return;
-            }
-          }
-        }
-        else {
-          error!("Failed deserialize register result, not ContactProtocol");
-          thread_state_clone.store(7, Ordering::Relaxed);
-          return;
-        }
+      Ok(Protocols::CPType(ContactProtocol::Response { req_id: REGISTER_REQ_ID, state, reply, .. })) => {
+        return registered(state, reply);
       },
-      Err(e) => {thread_err(format!("Failed received register result: {}", e.to_string()), 8);return;}
+      Ok(_) => {debug!("Skip frame received while registering");},
+      Err(e) if e.is_timeout() => {},
+      Err(e) => {return Err(RegisterError::Failed(format!("Failed received register result: {}", e)));}
+    }
+  }
+  Err(RegisterError::Failed("No register response from server".to_string()))
+}
+
+/// `register` without blocking the runtime thread.
+#[cfg(feature = "async")]
+async fn register_async(socket: &mut AsyncSocket, codec: Codec, credentials: Credentials, timeout: Duration) -> Result<(Handshake, String), RegisterError> {
+  let deadline = tokio::time::Instant::now() + timeout;
+  match tokio::time::timeout_at(deadline, socket.send_json(&register_msg(codec, credentials))).await {
+    Ok(sent) => {sent.map_err(|e| RegisterError::Failed(format!("Failed send register request: {}", e)))?;},
+    Err(_) => {return Err(RegisterError::Failed("Server unreachable, register request not sent".to_string()));}
+  }
+  loop {
+    match tokio::time::timeout_at(deadline, socket.recv_json()).await {
+      Ok(Ok(Protocols::CPType(ContactProtocol::Response { req_id: REGISTER_REQ_ID, state, reply, .. }))) => {
+        return registered(state, reply);
+      },
+      Ok(Ok(_)) => {debug!("Skip frame received while registering");},
+      Ok(Err(e)) => {return Err(RegisterError::Failed(format!("Failed received register result: {}", e)));},
+      Err(_) => {return Err(RegisterError::Failed("No register response from server".to_string()));}
+    }
+  }
+}
+
+/// A DEALER socket identified as `client_id`, connected to `config.connect` with CURVE when a server key is pinned.
+fn open_socket(zmq_ctx: &zmq::Context, config: &ClientConfig, client_id: &str) -> Result<zmq::Socket, Box<dyn std::error::Error>> {
+  let socket = zmq_ctx.socket(zmq::DEALER).map_err(|e| format!("Failed to create socket: {}", e))?;
+  socket.set_identity(client_id.as_bytes()).map_err(|e| format!("Failed to set Identity due to {}", e))?;
+  if let Some(server_key) = &config.curve.server_key {
+    let keys = CurveKeys::load_or_generate(&config.curve.keys)
+      .and_then(|keys|{keys.apply_client(&socket, server_key)?;Ok(keys)})
+      .map_err(|e| format!("Failed to enable CURVE: {}", e))?;
+    info!("CURVE enabled, pinned server key {}, own public key {}", server_key, keys.public_key);
+  } else {
+    warn!("No server key configured, traffic is not encrypted");
+  }
+  // Don't let an unreachable server block exiting on the goodbye message, nor sending while there is no pipe to it
+  socket.set_linger(1000).map_err(|e| format!("Failed to set linger: {}", e))?;
+  socket.set_sndtimeo(config.reply_timeout().as_millis() as i32).map_err(|e| format!("Failed to set send timeout: {}", e))?;
+  socket.connect(&config.connect).map_err(|e| format!("Failed to connect to server {}: {}", config.connect, e))?;
+  socket.set_rcvtimeo(100).map_err(|e| format!("Failed set regular recv timeout: {}", e))?;
+  info!("Socket connected, try to register client");
+  Ok(socket)
+}
+
+/// A registered connection to the chat server. A background thread owns the DEALER socket: it sends heartbeats,
+/// registers again after the connection is lost and hands notifications to subscribers.
+pub struct ChatClient {
+  client_id: String,
+  handshake: Handshake,
+  reply_timeout: Duration,
+  commands: mpsc::Sender<DealerCmd>,
+  subscribers: Arc<Mutex<Vec<Subscriber>>>,
+  dealer_handle: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl ChatClient {
+  /// Connect to `config.connect` and register as `client_id` with `password`.
+  pub fn connect(config: &ClientConfig, client_id: &str, password: &str) -> Result<ChatClient, Box<dyn std::error::Error>> {
+    ChatClient::connect_with(&zmq::Context::new(), config, client_id, password)
+  }
+
+  /// `connect` with a socket from `zmq_ctx`, an `inproc` endpoint is only reachable from the context it was bound in.
+  pub fn connect_with(zmq_ctx: &zmq::Context, config: &ClientConfig, client_id: &str, password: &str) -> Result<ChatClient, Box<dyn std::error::Error>> {
+    let socket = open_socket(zmq_ctx, config, client_id)?;
+    let (handshake, session_token) = register(&socket, config.codec, Credentials::Password(password.to_string()), config.register_timeout())?;
+    info!("Register successfully, protocol v{} in {:?} with {:?}", handshake.version, handshake.codec(), handshake.capabilities);
+    let (commands, command_receiver) = mpsc::channel();
+    let subscribers = Arc::new(Mutex::new(Vec::new()));
+    let dealer = Dealer::new(config, password, session_token, handshake.codec(), Arc::clone(&subscribers));
+    let dealer_handle = std::thread::spawn(move ||{dealer.run(socket, command_receiver)});
+    Ok(ChatClient { client_id: client_id.to_string(), handshake, reply_timeout: config.reply_timeout(), commands, subscribers, dealer_handle: Mutex::new(Some(dealer_handle)) })
+  }
+
+  pub fn client_id(&self) -> &str {
+    &self.client_id
+  }
+
+  /// Protocol version and capabilities agreed at the first register.
+  pub fn handshake(&self) -> &Handshake {
+    &self.handshake
+  }
+
+  /// Notifications and connection changes from now on. The channel closes once the client is closed or kicked.
+  pub fn subscribe(&self) -> mpsc::Receiver<ClientEvent> {
+    let (sender, receiver) = mpsc::channel();
+    self.subscribers.lock().unwrap().push(Subscriber::Blocking(sender));
+    receiver
+  }
+
+  /// False once the server kicked this client or the background thread stopped otherwise.
+  pub fn is_running(&self) -> bool {
+    self.dealer_handle.lock().unwrap().as_ref().is_some_and(|handle| !handle.is_finished())
+  }
+
+  /// Send `msg` and wait for its response. A response other than ACCEPTED comes back as `Err` with its state and reason.
+  pub fn request(&self, msg: ContactProtocol) -> Result<Reply, String> {
+    let (reply_sender, reply_receiver) = mpsc::channel();
+    if self.commands.send(DealerCmd::Request { msg, reply: Responder::Blocking(reply_sender), timeout: self.reply_timeout }).is_err() {
+      return Err("DEALER thread gone".to_string());
+    }
+    match reply_receiver.recv() {
+      Ok(response) => response_reply(response),
+      Err(_) => Err("DEALER thread dropped the request".to_string()),
+    }
+  }
+
+  fn run<T>(&self, call: Call<T>) -> Result<T, String> {
+    self.request(call.msg).and_then(call.read)
+  }
+
+  pub fn command(&self, command: ClientCommand) -> Result<Reply, String> {
+    self.run(Call::command(command))
+  }
+
+  /// Run a command the server serves with a custom handler.
+  pub fn call(&self, name: &str, args: serde_json::Value) -> Result<Reply, String> {
+    self.run(Call::custom(name, args))
+  }
+
+  /// Send a direct message, the reply tells whether it was delivered or queued.
+  pub fn send_message(&self, target: &str, content: MessageType) -> Result<Reply, String> {
+    self.run(Call::send_message(target, content))
+  }
+
+  pub fn send_room(&self, room: &str, content: MessageType) -> Result<Reply, String> {
+    self.run(Call::send_room(room, content))
+  }
+
+  /// Every known account with its presence, offline ones included.
+  pub fn list_clients(&self) -> Result<Vec<ClientPresence>, String> {
+    self.run(Call::list_clients())
+  }
+
+  pub fn set_presence(&self, availability: Availability, status: Option<String>) -> Result<(), String> {
+    self.run(Call::done(ClientCommand::SetPresence { availability, status }))
+  }
+
+  /// One page of stored direct messages, newest first, and the total number available.
+  pub fn history(&self, peer: Option<String>, offset: usize, limit: usize) -> Result<(Vec<HistoryEntry>, usize), String> {
+    self.run(Call::history(peer, offset, limit))
+  }
+
+  pub fn publish_key(&self, public_key: String) -> Result<(), String> {
+    self.run(Call::done(ClientCommand::PublishKey { public_key }))
+  }
+
+  /// Ask the server for the public key `client_id` published.
+  pub fn fetch_key(&self, client_id: &str) -> Result<String, String> {
+    self.run(Call::fetch_key(client_id))
+  }
+
+  /// Tell the sender of `msg_id` it was read, without waiting for the server.
+  pub fn mark_read(&self, msg_id: u64) {
+    self.commands.send(DealerCmd::Send { msg: client_control(ClientCommand::MarkRead { msg_id }) }).unwrap_or_else(|_|{warn!("DEALER thread gone, read receipt dropped")});
+  }
+
+  /// Unregister and wait for the background thread to stop. Dropping the client does the same.
+  pub fn close(&self) {
+    let _ = self.commands.send(DealerCmd::Shutdown);
+    if let Some(handle) = self.dealer_handle.lock().unwrap().take() {
+      handle.join().unwrap_or_else(|_|{error!("DEALER thread panicked")});
+    }
+  }
+}
+
+impl Drop for ChatClient {
+  fn drop(&mut self) {
+    self.close();
+  }
+}
+
+/// `ChatClient` for Tokio. A task owns the DEALER socket and sleeps until the socket's `ZMQ_FD`, a command
+/// or the next timer wakes it, so callers await requests alongside notifications and their own input.
+#[cfg(feature = "async")]
+pub struct AsyncChatClient {
+  client_id: String,
+  handshake: Handshake,
+  reply_timeout: Duration,
+  commands: tokio::sync::mpsc::UnboundedSender<DealerCmd>,
+  subscribers: Arc<Mutex<Vec<Subscriber>>>,
+  dealer_handle: Option<tokio::task::JoinHandle<()>>,
+}
+
+#[cfg(feature = "async")]
+impl AsyncChatClient {
+  /// Connect to `config.connect` and register as `client_id` with `password`. Must be called from within a Tokio runtime.
+  pub async fn connect(config: &ClientConfig, client_id: &str, password: &str) -> Result<AsyncChatClient, Box<dyn std::error::Error>> {
+    AsyncChatClient::connect_with(&zmq::Context::new(), config, client_id, password).await
+  }
+
+  /// `connect` with a socket from `zmq_ctx`, an `inproc` endpoint is only reachable from the context it was bound in.
+  pub async fn connect_with(zmq_ctx: &zmq::Context, config: &ClientConfig, client_id: &str, password: &str) -> Result<AsyncChatClient, Box<dyn std::error::Error>> {
+    let mut socket = AsyncSocket::new(open_socket(zmq_ctx, config, client_id)?).map_err(|e| format!("Failed to watch socket: {}", e))?;
+    let (handshake, session_token) = register_async(&mut socket, config.codec, Credentials::Password(password.to_string()), config.register_timeout()).await?;
+    info!("Register successfully, protocol v{} in {:?} with {:?}", handshake.version, handshake.codec(), handshake.capabilities);
+    let (commands, command_receiver) = tokio::sync::mpsc::unbounded_channel();
+    let subscribers = Arc::new(Mutex::new(Vec::new()));
+    let dealer = Dealer::new(config, password, session_token, handshake.codec(), Arc::clone(&subscribers));
+    let dealer_handle = tokio::spawn(dealer.run_async(socket, command_receiver));
+    Ok(AsyncChatClient { client_id: client_id.to_string(), handshake, reply_timeout: config.reply_timeout(), commands, subscribers, dealer_handle: Some(dealer_handle) })
+  }
+
+  pub fn client_id(&self) -> &str {
+    &self.client_id
+  }
+
+  /// Protocol version and capabilities agreed at the first register.
+  pub fn handshake(&self) -> &Handshake {
+    &self.handshake
+  }
+
+  /// Notifications and connection changes from now on. The channel closes once the client is closed or kicked.
+  pub fn subscribe(&self) -> tokio::sync::mpsc::UnboundedReceiver<ClientEvent> {
+    let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+    self.subscribers.lock().unwrap().push(Subscriber::Async(sender));
+    receiver
+  }
+
+  /// False once the server kicked this client or the background task stopped otherwise.
+  pub fn is_running(&self) -> bool {
+    self.dealer_handle.as_ref().is_some_and(|handle| !handle.is_finished())
+  }
+
+  /// Send `msg` and wait for its response. A response other than ACCEPTED comes back as `Err` with its state and reason.
+  pub async fn request(&self, msg: ContactProtocol) -> Result<Reply, String> {
+    let (reply_sender, reply_receiver) = tokio::sync::oneshot::channel();
+    if self.commands.send(DealerCmd::Request { msg, reply: Responder::Async(reply_sender), timeout: self.reply_timeout }).is_err() {
+      return Err("DEALER task gone".to_string());
     }
-    match socket.set_rcvtimeo(100) {
-      Ok(_) => {debug!("set regular recv timeout");},
-      Err(e) => {thread_err(format!("Failed set regular recv timeout: {}", e.to_string()), 11);return;}
+    match reply_receiver.await {
+      Ok(response) => response_reply(response),
+      Err(_) => Err("DEALER task dropped the request".to_string()),
     }
-    info!("Listening thread ok");
-    thread_state_clone.store(1, Ordering::Relaxed);
+  }
+
+  async fn run<T>(&self, call: Call<T>) -> Result<T, String> {
+    self.request(call.msg).await.and_then(call.read)
+  }
+
+  pub async fn command(&self, command: ClientCommand) -> Result<Reply, String> {
+    self.run(Call::command(command)).await
+  }
+
+  /// Run a command the server serves with a custom handler.
+  pub async fn call(&self, name: &str, args: serde_json::Value) -> Result<Reply, String> {
+    self.run(Call::custom(name, args)).await
+  }
+
+  /// Send a direct message, the reply tells whether it was delivered or queued.
+  pub async fn send_message(&self, target: &str, content: MessageType) -> Result<Reply, String> {
+    self.run(Call::send_message(target, content)).await
+  }
+
+  pub async fn send_room(&self, room: &str, content: MessageType) -> Result<Reply, String> {
+    self.run(Call::send_room(room, content)).await
+  }
+
+  /// Every known account with its presence, offline ones included.
+  pub async fn list_clients(&self) -> Result<Vec<ClientPresence>, String> {
+    self.run(Call::list_clients()).await
+  }
+
+  pub async fn set_presence(&self, availability: Availability, status: Option<String>) -> Result<(), String> {
+    self.run(Call::done(ClientCommand::SetPresence { availability, status })).await
+  }
+
+  /// One page of stored direct messages, newest first, and the total number available.
+  pub async fn history(&self, peer: Option<String>, offset: usize, limit: usize) -> Result<(Vec<HistoryEntry>, usize), String> {
+    self.run(Call::history(peer, offset, limit)).await
+  }
+
+  pub async fn publish_key(&self, public_key: String) -> Result<(), String> {
+    self.run(Call::done(ClientCommand::PublishKey { public_key })).await
+  }
+
+  /// Ask the server for the public key `client_id` published.
+  pub async fn fetch_key(&self, client_id: &str) -> Result<String, String> {
+    self.run(Call::fetch_key(client_id)).await
+  }
+
+  /// Tell the sender of `msg_id` it was read, without waiting for the server.
+  pub fn mark_read(&self, msg_id: u64) {
+    self.commands.send(DealerCmd::Send { msg: client_control(ClientCommand::MarkRead { msg_id }) }).unwrap_or_else(|_|{warn!("DEALER task gone, read receipt dropped")});
+  }
+
+  /// Unregister and wait for the background task to stop. Dropping the client unregisters without waiting.
+  pub async fn close(&mut self) {
+    let _ = self.commands.send(DealerCmd::Shutdown);
+    if let Some(handle) = self.dealer_handle.take() {
+      handle.await.unwrap_or_else(|_|{error!("DEALER task panicked")});
+    }
+  }
+}
+
+#[cfg(feature = "async")]
+impl Drop for AsyncChatClient {
+  fn drop(&mut self) {
+    let _ = self.commands.send(DealerCmd::Shutdown);
+  }
+}
+
+/// State of the background thread or task behind a client, everything but the socket.
+struct Dealer {
+  config: ClientConfig,
+  password: String,
+  session_token: String,
+  /// Agreed at the last register, frames are only sent in it once registered.
+  codec: Codec,
+  subscribers: Arc<Mutex<Vec<Subscriber>>>,
+  pending: HashMap<u64, PendingRequest>,
+  /// Frames to send by their req_id, the run loop sends them with whatever socket it owns.
+  outbox: Vec<(u64, Protocols)>,
+  next_req_id: u64,
+  last_heartbeat: Instant,
+  last_heard: Instant,
+  connected: bool,
+  backoff: Duration,
+  next_attempt: Instant,
+  server_down_at: Option<Instant>,
+}
+
+impl Dealer {
+  fn new(config: &ClientConfig, password: &str, session_token: String, codec: Codec, subscribers: Arc<Mutex<Vec<Subscriber>>>) -> Dealer {
+    Dealer {
+      config: config.clone(),
+      password: password.to_string(),
+      session_token,
+      codec,
+      subscribers,
+      pending: HashMap::new(),
+      outbox: Vec::new(),
+      next_req_id: REGISTER_REQ_ID + 1,
+      last_heartbeat: Instant::now(),
+      last_heard: Instant::now(),
+      connected: true,
+      backoff: RECONNECT_MIN_BACKOFF,
+      next_attempt: Instant::now(),
+      server_down_at: None,
+    }
+  }
+
+  fn publish(&self, event: ClientEvent) {
+    self.subscribers.lock().unwrap().retain(|subscriber| subscriber.send(event.clone()));
+  }
+
+  fn run(mut self, socket: zmq::Socket, commands: mpsc::Receiver<DealerCmd>) {
+    debug!("Child thread with DEALER start");
     loop {
-      let cmd: String = thread_receiver.try_recv().unwrap_or("".to_string());
-      if !cmd.is_empty(){
-        match cmd.as_str() {
-          "shutdown" => {
-            debug!("DEALER thread exit");
-            let quit_msg = 
-              Protocols::CPType(ContactProtocol::ClientControl { state: MsgStatus::SUBMITTED, command: "unregister".to_string(), cmd_args: None, time: Utc::now() });
-            socket.send_json(&quit_msg, Some(0))
-              .unwrap_or_else(|e|{warn!("Error {} occured during say goodbye", e.to_string())});
-            break;
-          },
-          _ => {warn!("DEALER received Unknow cmd");}
-        }
+      self.expire();
+      if self.reconnect_due() {
+        let registered = register(&socket, self.config.codec, Credentials::Token(self.session_token.clone()), self.config.register_timeout())
+          .or_else(|e| match e {
+            RegisterError::Rejected(reason) => {
+              debug!("Session token refused ({}), register with password", reason);
+              register(&socket, self.config.codec, Credentials::Password(self.password.clone()), self.config.register_timeout())
+            },
+            failed => Err(failed),
+          });
+        self.reconnected(registered);
+      }
+      self.heartbeat();
+      let running = commands.try_recv().map_or(true, |cmd| self.handle_command(cmd));
+      self.flush(&socket);
+      if !running {
+        break;
       }
-      let raw_msg: Protocols;
       match socket.recv_json(Some(0)) {
-        Ok(_val) => {raw_msg = _val;debug!("Msg received");},
-        Err(e) => {
-          if let Some(zmq_e) = e.downcast_ref::<zmq::Error>() {
-            if zmq_e.eq(&zmq::Error::EAGAIN){continue;}
-            error!("Zmq err occured: {}", zmq_e.to_string());
-          }else if let Some(json_e) = e.downcast_ref::<serde_json::Error>(){
-            error!("Json err occured: {}", json_e.to_string());
+        Ok(raw_msg) => {
+          if !self.handle_frame(raw_msg) {
+            break;
           }
-          else if let Some(str_e) = e.downcast_ref::<std::string::FromUtf8Error>(){
-            error!("String err occured: {}", str_e.to_string());
+        },
+        Err(e) if e.is_timeout() => {},
+        Err(e) => {error!("Failed to receive: {}", e);}
+      }
+    }
+    // Closes every subscription
+    self.subscribers.lock().unwrap().clear();
+  }
+
+  #[cfg(feature = "async")]
+  async fn run_async(mut self, mut socket: AsyncSocket, mut commands: tokio::sync::mpsc::UnboundedReceiver<DealerCmd>) {
+    debug!("DEALER task start");
+    loop {
+      self.expire();
+      if self.reconnect_due() {
+        let registered = match register_async(&mut socket, self.config.codec, Credentials::Token(self.session_token.clone()), self.config.register_timeout()).await {
+          Err(RegisterError::Rejected(reason)) => {
+            debug!("Session token refused ({}), register with password", reason);
+            register_async(&mut socket, self.config.codec, Credentials::Password(self.password.clone()), self.config.register_timeout()).await
+          },
+          registered => registered,
+        };
+        self.reconnected(registered);
+      }
+      self.heartbeat();
+      self.flush_async(&mut socket).await;
+      let wakeup = tokio::time::Instant::from_std(self.next_wakeup());
+      tokio::select! {
+        cmd = commands.recv() => {
+          // Every handle to the client is gone, same as being told to shut down
+          let running = self.handle_command(cmd.unwrap_or(DealerCmd::Shutdown));
+          self.flush_async(&mut socket).await;
+          if !running {
+            break;
           }
-          else {
-            error!("Unknow err occured: {}", e.to_string());
+        },
+        received = socket.recv_json() => {
+          match received {
+            Ok(raw_msg) => {
+              if !self.handle_frame(raw_msg) {
+                break;
+              }
+            },
+            Err(e) => {error!("Failed to receive: {}", e);}
           }
-          continue;
-        }
-      }
-      match raw_msg {
-        Protocols::CPType(_val) => {},
-        Protocols::NPType(_val) => {},
+        },
+        _ = tokio::time::sleep_until(wakeup) => {},
       }
     }
-  });
+    // Closes every subscription
+    self.subscribers.lock().unwrap().clear();
+  }
 
-  info!("Waiting for DEALER thread");
-  loop {
-    let val = thread_state.load(Ordering::Relaxed);
-    if val != 0{
-      if val != 1{
-        error!("DEALER thread err detected, exiting...");
-        return;
+  /// `msg` with the current session token, which changes when registering again.
+  fn stamp(&self, mut msg: Protocols) -> Protocols {
+    if let Protocols::CPType(request) = &mut msg {
+      request.set_session(&self.session_token);
+    }
+    msg
+  }
+
+  /// Send the queued frames. A request that cannot be sent fails right away.
+  fn flush(&mut self, socket: &zmq::Socket) {
+    for (req_id, msg) in std::mem::take(&mut self.outbox) {
+      let sent = socket.send_frame(&self.stamp(msg), self.codec, Some(0));
+      self.sent(req_id, sent);
+    }
+  }
+
+  /// `flush` without blocking the runtime thread.
+  #[cfg(feature = "async")]
+  async fn flush_async(&mut self, socket: &mut AsyncSocket) {
+    for (req_id, msg) in std::mem::take(&mut self.outbox) {
+      let msg = self.stamp(msg);
+      let sent = tokio::time::timeout(self.config.reply_timeout(), AsyncZmqJsonClient::send_frame(socket, &msg, self.codec)).await
+        .unwrap_or_else(|_| Err(ChatError::Transport(std::io::ErrorKind::WouldBlock.into())));
+      self.sent(req_id, sent);
+    }
+  }
+
+  fn sent(&mut self, req_id: u64, sent: Result<(), ChatError>) {
+    match sent {
+      Ok(_) => {debug!("Request {} sent", req_id);},
+      Err(e) => {
+        error!("Failed to send request {}: {}", req_id, e);
+        if let Some(request) = self.pending.remove(&req_id) {
+          request.reply.send(failed_response(req_id, e.to_string()));
+        }
       }
-      else {
-        debug!("Receive DEALER thread ok");
-        break;
+    }
+  }
+
+  /// Time out requests and notice when the server went away.
+  fn expire(&mut self) {
+    let now = Instant::now();
+    let expired: Vec<u64> = self.pending.iter().filter(|(_, request)| request.deadline <= now).map(|(req_id, _)| *req_id).collect();
+    for req_id in expired {
+      warn!("Request {} timed out", req_id);
+      if let Some(request) = self.pending.remove(&req_id) {
+        request.reply.send(failed_response(req_id, "Timed out".to_string()));
       }
     }
+    if self.connected && self.last_heard.elapsed() > self.config.heartbeat_interval() * MISSED_HEARTBEATS {
+      warn!("Nothing heard from server for {:?}", self.last_heard.elapsed());
+      self.publish(ClientEvent::ConnectionLost);
+      self.connected = false;
+      self.next_attempt = Instant::now();
+    }
+    if self.server_down_at.is_some_and(|at| Instant::now() >= at) {
+      self.server_down_at = None;
+      info!("Server went down, reconnecting");
+      self.publish(ClientEvent::ConnectionLost);
+      self.connected = false;
+      self.backoff = RECONNECT_MIN_BACKOFF;
+      self.next_attempt = Instant::now() + RECONNECT_MIN_BACKOFF;
+    }
   }
-  info!("Shell ok");
-  loop {
-    let user_input = input("Enter command: ");
-    let mut cmd_it = user_input.split_whitespace();
-    let cmd_type;
-    match cmd_it.next() {
-      Some(_val) => {cmd_type = _val;}
-      None => {
-        warn!("No cmd given, try again");
-        continue;
+
+  fn reconnect_due(&self) -> bool {
+    !self.connected && Instant::now() >= self.next_attempt
+  }
+
+  fn reconnected(&mut self, registered: Result<(Handshake, String), RegisterError>) {
+    match registered {
+      Ok((handshake, token)) => {
+        info!("Registered again, protocol v{} in {:?} with {:?}", handshake.version, handshake.codec(), handshake.capabilities);
+        self.publish(ClientEvent::Reconnected);
+        self.session_token = token;
+        self.codec = handshake.codec();
+        self.connected = true;
+        self.last_heard = Instant::now();
+        self.backoff = RECONNECT_MIN_BACKOFF;
+        let mut unacked: Vec<(&u64, &PendingRequest)> = self.pending.iter().collect();
+        unacked.sort_by_key(|(req_id, _)| **req_id);
+        for (req_id, request) in unacked {
+          debug!("Resend request {}", req_id);
+          self.outbox.push((*req_id, request.msg.clone()));
+        }
+      },
+      Err(e) => {
+        warn!("Reconnect failed: {}, retry in {:?}", e, self.backoff);
+        self.next_attempt = Instant::now() + self.backoff;
+        self.backoff = (self.backoff * 2).min(self.config.reconnect_max_backoff());
       }
     }
-    match cmd_type {
-      "q" => {
-        info!("Shutdown cmd received, quiting...");
-        main_sender.send("shutdown".to_string()).unwrap();
-        break;
+  }
+
+  fn heartbeat(&mut self) {
+    if self.connected && self.last_heartbeat.elapsed() >= self.config.heartbeat_interval() {
+      self.last_heartbeat = Instant::now();
+      let heartbeat_msg =
+        Protocols::CPType(ContactProtocol::ClientControl { req_id: UNTRACKED_REQ_ID, session: None, state: MsgStatus::SUBMITTED, command: ClientCommand::Heartbeat, time: Utc::now() });
+      self.outbox.push((UNTRACKED_REQ_ID, heartbeat_msg));
+    }
+  }
+
+  /// When a request times out, a heartbeat is due or the connection needs checking on, whichever comes first.
+  #[cfg(feature = "async")]
+  fn next_wakeup(&self) -> Instant {
+    let heartbeat_interval = self.config.heartbeat_interval();
+    let mut wakeup = self.pending.values().map(|request| request.deadline).min().unwrap_or(Instant::now() + heartbeat_interval);
+    if self.connected {
+      wakeup = wakeup.min(self.last_heartbeat + heartbeat_interval).min(self.last_heard + heartbeat_interval * MISSED_HEARTBEATS);
+    } else {
+      wakeup = wakeup.min(self.next_attempt);
+    }
+    if let Some(at) = self.server_down_at {
+      wakeup = wakeup.min(at);
+    }
+    wakeup
+  }
+
+  /// False once the client is shutting down.
+  fn handle_command(&mut self, cmd: DealerCmd) -> bool {
+    match cmd {
+      DealerCmd::Shutdown => {
+        debug!("DEALER thread exit");
+        if self.connected {
+          let quit_msg =
+            Protocols::CPType(ContactProtocol::ClientControl { req_id: self.next_req_id, session: None, state: MsgStatus::SUBMITTED, command: ClientCommand::Unregister, time: Utc::now() });
+          self.outbox.push((self.next_req_id, quit_msg));
+        }
+        return false;
       },
-      "send" => {
-        
+      DealerCmd::Request { mut msg, reply, timeout } => {
+        let req_id = self.next_req_id;
+        self.next_req_id += 1;
+        msg.set_req_id(req_id);
+        let msg = Protocols::CPType(msg);
+        if !self.connected {
+          debug!("Request {} held until reconnected", req_id);
+          self.pending.insert(req_id, PendingRequest { msg, reply, deadline: Instant::now() + timeout });
+          return true;
+        }
+        self.outbox.push((req_id, msg.clone()));
+        self.pending.insert(req_id, PendingRequest { msg, reply, deadline: Instant::now() + timeout });
       },
-      _ => {
-        warn!("Unknow cmd");
-        continue;
-      }
+      DealerCmd::Send { mut msg } => {
+        if !self.connected {
+          debug!("Not connected, dropped untracked request");
+          return true;
+        }
+        msg.set_req_id(UNTRACKED_REQ_ID);
+        self.outbox.push((UNTRACKED_REQ_ID, Protocols::CPType(msg)));
+      },
+    }
+    true
+  }
+
+  /// False once the server kicked this client.
+  fn handle_frame(&mut self, raw_msg: Protocols) -> bool {
+    self.last_heard = Instant::now();
+    debug!("Msg received");
+    match raw_msg {
+      Protocols::CPType(ContactProtocol::Response { state: MsgStatus::REJECTED, reply: Reply::Reason(reason), .. }) if reason == "register" => {
+        // The server restarted or evicted us, the rejected request stays pending and is resent
+        if self.connected {
+          warn!("Server no longer knows this client, registering again");
+          self.connected = false;
+          self.next_attempt = Instant::now();
+        }
+      },
+      Protocols::CPType(response) if response.req_id() == UNTRACKED_REQ_ID => {
+        if let ContactProtocol::Response { state, reply, .. } = response {
+          debug!("Heartbeat or read receipt answered {}: {}", state, reply);
+        }
+      },
+      Protocols::CPType(response) if response.req_id() == REGISTER_REQ_ID => {debug!("Late register response ignored");},
+      Protocols::CPType(response) => {
+        match self.pending.remove(&response.req_id()) {
+          Some(request) => {
+            if !request.reply.send(response) {
+              warn!("Caller stopped waiting for the response");
+            }
+          },
+          None => {
+            if let ContactProtocol::Response { state, reply, .. } = response {
+              warn!("Response nobody waits for: {} {}", state, reply);
+            } else {
+              warn!("Response received but no command is waiting for it");
+            }
+          }
+        }
+      },
+      Protocols::NPType(notification @ NotifyProtocol::Kicked { .. }) => {
+        self.publish(ClientEvent::Notification(notification));
+        for (req_id, request) in self.pending.drain() {
+          request.reply.send(failed_response(req_id, "Kicked".to_string()));
+        }
+        // Registering again would only get kicked again, leave it to the user
+        return false;
+      },
+      Protocols::NPType(notification @ NotifyProtocol::ServerShutdown { grace_secs, .. }) => {
+        self.publish(ClientEvent::Notification(notification));
+        self.server_down_at = Some(Instant::now() + Duration::from_secs(grace_secs));
+      },
+      Protocols::NPType(notification) => {
+        self.publish(ClientEvent::Notification(notification));
+      },
+    }
+    true
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn dealer(config: ClientConfig) -> Dealer {
+    Dealer::new(&config, "pw", "token".to_string(), Codec::Json, Arc::new(Mutex::new(Vec::new())))
+  }
+
+  /// Queue a request for `target` and return where its response arrives.
+  fn request(dealer: &mut Dealer, target: &str, timeout: Duration) -> mpsc::Receiver<ContactProtocol> {
+    let (reply, response) = mpsc::channel();
+    let msg = ContactProtocol::User2UserMsg { req_id: 0, session: None, state: MsgStatus::SUBMITTED, target: target.to_string(),
+      content: MessageType::TextMsg { content: "hi".to_string() }, time: Utc::now() };
+    assert!(dealer.handle_command(DealerCmd::Request { msg, reply: Responder::Blocking(reply), timeout }));
+    response
+  }
+
+  fn outbox_ids(dealer: &mut Dealer) -> Vec<u64> {
+    std::mem::take(&mut dealer.outbox).into_iter().map(|(req_id, _)| req_id).collect()
+  }
+
+  #[test]
+  fn backoff_is_capped() {
+    let mut dealer = dealer(ClientConfig { reconnect_max_backoff_secs: 5, ..ClientConfig::default() });
+    dealer.connected = false;
+    let mut waits = Vec::new();
+    for _ in 0..6 {
+      waits.push(dealer.backoff.as_secs());
+      dealer.reconnected(Err(RegisterError::Failed("Timed out".to_string())));
+      assert!(!dealer.reconnect_due());
+    }
+    assert_eq!(waits, [1, 2, 4, 5, 5, 5]);
+    // A successful register starts over
+    dealer.reconnected(Ok((Handshake::current(), "new-token".to_string())));
+    assert_eq!(dealer.backoff, RECONNECT_MIN_BACKOFF);
+    assert_eq!(dealer.session_token, "new-token");
+  }
+
+  #[test]
+  fn held_requests_are_resent_after_reconnect() {
+    let mut dealer = dealer(ClientConfig::default());
+    let _answered = request(&mut dealer, "bob", Duration::from_secs(5));
+    let _unanswered = request(&mut dealer, "carol", Duration::from_secs(5));
+    assert_eq!(outbox_ids(&mut dealer), [2, 3]);
+    dealer.handle_frame(Protocols::CPType(ContactProtocol::Response { req_id: 2, state: MsgStatus::ACCEPTED, reply: Reply::Done, time: Utc::now() }));
+
+    dealer.connected = false;
+    let _held = request(&mut dealer, "dave", Duration::from_secs(5));
+    assert!(outbox_ids(&mut dealer).is_empty());
+    dealer.reconnected(Ok((Handshake::current(), "token".to_string())));
+    assert!(dealer.connected);
+    // The unanswered request goes out again, in order with the one held meanwhile
+    assert_eq!(outbox_ids(&mut dealer), [3, 4]);
+  }
+
+  #[test]
+  fn held_requests_expire_at_their_deadline() {
+    let mut dealer = dealer(ClientConfig::default());
+    dealer.connected = false;
+    let response = request(&mut dealer, "bob", Duration::from_millis(200));
+    dealer.expire();
+    assert!(response.try_recv().is_err());
+    std::thread::sleep(Duration::from_millis(250));
+    dealer.expire();
+    match response.try_recv().unwrap() {
+      ContactProtocol::Response { req_id: 2, state: MsgStatus::FAILED, reply: Reply::Reason(reason), .. } => assert_eq!(reason, "Timed out"),
+      _ => panic!("expected a timeout response"),
     }
+    assert!(dealer.pending.is_empty());
+    dealer.reconnected(Ok((Handshake::current(), "token".to_string())));
+    assert!(dealer.outbox.is_empty());
   }
-  info!("Main thread done, waiting DEALER thread...");
-  dealer_handle.join().unwrap();
-  info!("Total exiting...");
 }
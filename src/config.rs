@@ -0,0 +1,309 @@
+use clap::{Parser, Subcommand};
+use serde::Deserialize;
+use std::{fs, path::{Path, PathBuf}, time::Duration};
+use crate::utils::Codec;
+
+/// Settings are read from the TOML file first, then flags (or their environment variables) override single keys.
+fn read_toml<T: for<'de> Deserialize<'de> + Default>(explicit: Option<&Path>, fallback: &str) -> Result<T, Box<dyn std::error::Error>> {
+  let path = match explicit {
+    Some(path) => path,
+    None if Path::new(fallback).exists() => Path::new(fallback),
+    None => return Ok(T::default()),
+  };
+  let text = fs::read_to_string(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+  Ok(toml::from_str(&text).map_err(|e| format!("Invalid config {}: {}", path.display(), e))?)
+}
+
+#[derive(Parser)]
+#[command(about = "Chat server")]
+pub struct ServerArgs {
+  /// Config file, `server.toml` is used when it exists.
+  #[arg(short, long)]
+  pub config: Option<PathBuf>,
+  /// Endpoint the ROUTER socket binds, e.g. `tcp://*:5555`.
+  #[arg(long)]
+  pub bind: Option<String>,
+  /// Endpoint the server shell connects to, derived from `bind` by default.
+  #[arg(long)]
+  pub control_connect: Option<String>,
+  #[arg(long, env = "RUST_LOG")]
+  pub log_level: Option<String>,
+  #[arg(long, env = "CHAT_HISTORY_PATH")]
+  pub history_path: Option<PathBuf>,
+  #[arg(long, env = "CHAT_USERS_PATH")]
+  pub users_path: Option<PathBuf>,
+  /// Evict clients that sent nothing for this long.
+  #[arg(long)]
+  pub client_timeout_secs: Option<u64>,
+  #[arg(long, env = "CHAT_QUEUE_CAP")]
+  pub queue_cap: Option<usize>,
+  #[arg(long, env = "CHAT_QUEUE_EXPIRY_SECS")]
+  pub queue_expiry_secs: Option<i64>,
+  /// Where undelivered messages are kept across a shutdown.
+  #[arg(long, env = "CHAT_QUEUE_PATH")]
+  pub queue_path: Option<PathBuf>,
+  /// Enable CURVE with the keypair at this path, generated on first use.
+  #[arg(long, env = "CHAT_CURVE_KEYS")]
+  pub curve_keys: Option<PathBuf>,
+  /// Only admit CURVE clients whose public key is listed in this file.
+  #[arg(long, env = "CHAT_CURVE_ALLOWLIST")]
+  pub curve_allowlist: Option<PathBuf>,
+  #[command(subcommand)]
+  pub command: Option<ServerSubcommand>,
+}
+
+#[derive(Subcommand)]
+pub enum ServerSubcommand {
+  /// Write a new CURVE keypair and print its public key.
+  Keygen{#[arg(default_value = "server_curve.json")] path: PathBuf},
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QueueSection {
+  pub max_per_user: usize,
+  pub expiry_secs: i64,
+  pub path: PathBuf,
+}
+
+impl Default for QueueSection {
+  fn default() -> Self {
+    QueueSection { max_per_user: 100, expiry_secs: 7 * 24 * 3600, path: PathBuf::from("chat_queue.json") }
+  }
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerCurveSection {
+  pub keys: Option<PathBuf>,
+  pub allowlist: Option<PathBuf>,
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+  pub bind: String,
+  pub control_connect: Option<String>,
+  pub log_level: String,
+  pub history_path: PathBuf,
+  pub users_path: PathBuf,
+  pub client_timeout_secs: u64,
+  pub queue: QueueSection,
+  pub curve: ServerCurveSection,
+}
+
+impl Default for ServerConfig {
+  fn default() -> Self {
+    ServerConfig {
+      bind: "tcp://*:5555".to_string(),
+      control_connect: None,
+      log_level: "error".to_string(),
+      history_path: PathBuf::from("chat_history.jsonl"),
+      users_path: PathBuf::from("chat_users.json"),
+      client_timeout_secs: 30,
+      queue: QueueSection::default(),
+      curve: ServerCurveSection::default(),
+    }
+  }
+}
+
+impl ServerConfig {
+  pub fn load(args: &ServerArgs) -> Result<ServerConfig, Box<dyn std::error::Error>> {
+    let mut config: ServerConfig = read_toml(args.config.as_deref(), "server.toml")?;
+    if let Some(val) = &args.bind {config.bind = val.clone();}
+    if let Some(val) = &args.control_connect {config.control_connect = Some(val.clone());}
+    if let Some(val) = &args.log_level {config.log_level = val.clone();}
+    if let Some(val) = &args.history_path {config.history_path = val.clone();}
+    if let Some(val) = &args.users_path {config.users_path = val.clone();}
+    if let Some(val) = args.client_timeout_secs {config.client_timeout_secs = val;}
+    if let Some(val) = args.queue_cap {config.queue.max_per_user = val;}
+    if let Some(val) = args.queue_expiry_secs {config.queue.expiry_secs = val;}
+    if let Some(val) = &args.queue_path {config.queue.path = val.clone();}
+    if let Some(val) = &args.curve_keys {config.curve.keys = Some(val.clone());}
+    if let Some(val) = &args.curve_allowlist {config.curve.allowlist = Some(val.clone());}
+    Ok(config)
+  }
+
+  /// Where the shell's control socket connects: `control_connect`, or `bind` with a wildcard host made local.
+  pub fn control_endpoint(&self) -> String {
+    match &self.control_connect {
+      Some(endpoint) => endpoint.clone(),
+      None => self.bind.replacen("://*:", "://localhost:", 1).replacen("://0.0.0.0:", "://localhost:", 1),
+    }
+  }
+}
+
+#[derive(Parser)]
+#[command(about = "Chat client")]
+pub struct ClientArgs {
+  /// Config file, `client.toml` is used when it exists.
+  #[arg(short, long)]
+  pub config: Option<PathBuf>,
+  /// Server endpoint, e.g. `tcp://chat.example.com:5555`.
+  #[arg(long)]
+  pub connect: Option<String>,
+  /// Skip the client id prompt.
+  #[arg(long)]
+  pub client_id: Option<String>,
+  #[arg(long, env = "RUST_LOG")]
+  pub log_level: Option<String>,
+  /// How long a command waits for the server's response.
+  #[arg(long)]
+  pub reply_timeout_secs: Option<u64>,
+  #[arg(long)]
+  pub register_timeout_secs: Option<u64>,
+  /// Interval between heartbeats, keep it well below the server's client timeout.
+  #[arg(long)]
+  pub heartbeat_secs: Option<u64>,
+  /// Longest wait between two reconnect attempts.
+  #[arg(long)]
+  pub reconnect_max_backoff_secs: Option<u64>,
+  /// Don't tell senders when their direct messages were shown here.
+  #[arg(long)]
+  pub no_read_receipts: bool,
+  /// Directory of the end-to-end key store.
+  #[arg(long, env = "CHAT_E2E_DIR")]
+  pub e2e_dir: Option<PathBuf>,
+  /// Enable CURVE and only talk to the server holding this public key.
+  #[arg(long, env = "CHAT_SERVER_KEY")]
+  pub server_key: Option<String>,
+  #[arg(long, env = "CHAT_CURVE_KEYS")]
+  pub curve_keys: Option<PathBuf>,
+  /// Wire format to ask the server for, `json` or `msgpack`.
+  #[arg(long)]
+  pub codec: Option<Codec>,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClientCurveSection {
+  pub server_key: Option<String>,
+  pub keys: PathBuf,
+}
+
+impl Default for ClientCurveSection {
+  fn default() -> Self {
+    ClientCurveSection { server_key: None, keys: PathBuf::from("client_curve.json") }
+  }
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClientConfig {
+  pub connect: String,
+  pub client_id: Option<String>,
+  pub log_level: String,
+  pub reply_timeout_secs: u64,
+  pub register_timeout_secs: u64,
+  pub heartbeat_secs: u64,
+  pub reconnect_max_backoff_secs: u64,
+  pub read_receipts: bool,
+  pub e2e_dir: PathBuf,
+  pub codec: Codec,
+  pub curve: ClientCurveSection,
+}
+
+impl Default for ClientConfig {
+  fn default() -> Self {
+    ClientConfig {
+      connect: "tcp://127.0.0.1:5555".to_string(),
+      client_id: None,
+      log_level: "error".to_string(),
+      reply_timeout_secs: 5,
+      register_timeout_secs: 5,
+      heartbeat_secs: 10,
+      reconnect_max_backoff_secs: 30,
+      read_receipts: true,
+      e2e_dir: PathBuf::from("."),
+      codec: Codec::Json,
+      curve: ClientCurveSection::default(),
+    }
+  }
+}
+
+impl ClientConfig {
+  pub fn load(args: &ClientArgs) -> Result<ClientConfig, Box<dyn std::error::Error>> {
+    let mut config: ClientConfig = read_toml(args.config.as_deref(), "client.toml")?;
+    if let Some(val) = &args.connect {config.connect = val.clone();}
+    if let Some(val) = &args.client_id {config.client_id = Some(val.clone());}
+    if let Some(val) = &args.log_level {config.log_level = val.clone();}
+    if let Some(val) = args.reply_timeout_secs {config.reply_timeout_secs = val;}
+    if let Some(val) = args.register_timeout_secs {config.register_timeout_secs = val;}
+    if let Some(val) = args.heartbeat_secs {config.heartbeat_secs = val;}
+    if let Some(val) = args.reconnect_max_backoff_secs {config.reconnect_max_backoff_secs = val;}
+    if args.no_read_receipts {config.read_receipts = false;}
+    if let Some(val) = &args.e2e_dir {config.e2e_dir = val.clone();}
+    if let Some(val) = &args.server_key {config.curve.server_key = Some(val.clone());}
+    if let Some(val) = &args.curve_keys {config.curve.keys = val.clone();}
+    if let Some(val) = args.codec {config.codec = val;}
+    Ok(config)
+  }
+
+  pub fn reply_timeout(&self) -> Duration {
+    Duration::from_secs(self.reply_timeout_secs)
+  }
+
+  pub fn register_timeout(&self) -> Duration {
+    Duration::from_secs(self.register_timeout_secs)
+  }
+
+  pub fn heartbeat_interval(&self) -> Duration {
+    Duration::from_secs(self.heartbeat_secs)
+  }
+
+  pub fn reconnect_max_backoff(&self) -> Duration {
+    Duration::from_secs(self.reconnect_max_backoff_secs)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn write_config(dir: &Path, text: &str) -> String {
+    let path = dir.join("config.toml");
+    fs::write(&path, text).unwrap();
+    path.display().to_string()
+  }
+
+  #[test]
+  fn defaults_use_an_unprivileged_port() {
+    let server = ServerConfig::default();
+    assert_eq!(server.bind, "tcp://*:5555");
+    assert_eq!(server.control_endpoint(), "tcp://localhost:5555");
+    assert_eq!(ClientConfig::default().connect, "tcp://127.0.0.1:5555");
+  }
+
+  #[test]
+  fn flags_override_the_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = write_config(dir.path(), "bind = \"tcp://*:6000\"\nclient_timeout_secs = 60\n[queue]\nmax_per_user = 5\n");
+    let from_file = ServerConfig::load(&ServerArgs::parse_from(["server", "--config", &path])).unwrap();
+    assert_eq!(from_file.bind, "tcp://*:6000");
+    assert_eq!(from_file.client_timeout_secs, 60);
+    assert_eq!(from_file.queue.max_per_user, 5);
+    assert_eq!(from_file.queue.expiry_secs, QueueSection::default().expiry_secs);
+
+    let overridden = ServerConfig::load(&ServerArgs::parse_from(["server", "--config", &path, "--bind", "tcp://*:7000"])).unwrap();
+    assert_eq!(overridden.bind, "tcp://*:7000");
+    assert_eq!(overridden.client_timeout_secs, 60);
+
+    let path = write_config(dir.path(), "connect = \"tcp://staging:6000\"\nheartbeat_secs = 3\n");
+    let client = ClientConfig::load(&ClientArgs::parse_from(["client", "--config", &path, "--heartbeat-secs", "4"])).unwrap();
+    assert_eq!(client.connect, "tcp://staging:6000");
+    assert_eq!(client.heartbeat_secs, 4);
+  }
+
+  #[test]
+  fn unknown_keys_are_rejected() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = write_config(dir.path(), "bnid = \"tcp://*:6000\"\n");
+    let e = ServerConfig::load(&ServerArgs::parse_from(["server", "--config", &path])).err().unwrap();
+    assert!(e.to_string().contains("bnid"), "unexpected error {}", e);
+
+    let path = write_config(dir.path(), "[curve]\nserver_key = \"x\"\nallowlist = \"keys.txt\"\n");
+    assert!(ClientConfig::load(&ClientArgs::parse_from(["client", "--config", &path])).is_err());
+    let missing = dir.path().join("missing.toml").display().to_string();
+    assert!(ServerConfig::load(&ServerArgs::parse_from(["server", "--config", &missing])).is_err());
+  }
+}
@@ -0,0 +1,157 @@
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashSet, fs, io::Write, path::Path};
+
+const ZAP_ENDPOINT: &str = "inproc://zeromq.zap.01";
+pub const ZAP_DOMAIN: &str = "chat";
+
+/// A CURVE keypair in Z85 text form, stored as JSON. The secret half never leaves this file.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct CurveKeys {
+  pub public_key: String,
+  pub secret_key: String,
+}
+
+impl CurveKeys {
+  pub fn generate() -> Result<CurveKeys, Box<dyn std::error::Error>> {
+    let pair = zmq::CurveKeyPair::new()?;
+    Ok(CurveKeys { public_key: pair.public_key, secret_key: pair.secret_key })
+  }
+
+  pub fn load(path: &Path) -> Result<CurveKeys, Box<dyn std::error::Error>> {
+    let keys: CurveKeys = serde_json::from_slice(&fs::read(path)?)?;
+    if keys.public_key.len() != 40 || keys.secret_key.len() != 40 {
+      return Err(format!("{} does not hold Z85 encoded CURVE keys", path.display()).into());
+    }
+    Ok(keys)
+  }
+
+  pub fn save(&self, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)?.write_all(&serde_json::to_vec_pretty(self)?)?;
+    Ok(())
+  }
+
+  /// Load the keypair at `path`, creating one there on first use.
+  pub fn load_or_generate(path: &Path) -> Result<CurveKeys, Box<dyn std::error::Error>> {
+    if path.exists() {
+      return CurveKeys::load(path);
+    }
+    let keys = CurveKeys::generate()?;
+    keys.save(path)?;
+    info!("Generated CURVE keypair {}, public key {}", path.display(), keys.public_key);
+    Ok(keys)
+  }
+
+  pub fn apply_server(&self, socket: &zmq::Socket) -> zmq::Result<()> {
+    socket.set_curve_server(true)?;
+    socket.set_curve_secretkey(&self.secret_key)?;
+    socket.set_zap_domain(ZAP_DOMAIN)
+  }
+
+  /// Connect as a CURVE client that only talks to the server holding `server_key`.
+  pub fn apply_client(&self, socket: &zmq::Socket, server_key: &str) -> zmq::Result<()> {
+    socket.set_curve_serverkey(server_key)?;
+    socket.set_curve_publickey(&self.public_key)?;
+    socket.set_curve_secretkey(&self.secret_key)
+  }
+}
+
+/// Z85 public keys, one per line. Blank lines and `#` comments are skipped. `#` is also a Z85 digit,
+/// so a comment after a key needs whitespace before it.
+pub fn load_allowlist(path: &Path) -> Result<HashSet<String>, Box<dyn std::error::Error>> {
+  let mut keys = HashSet::new();
+  for (line_no, line) in fs::read_to_string(path)?.lines().enumerate() {
+    let Some(key) = line.split_whitespace().next() else {
+      continue;
+    };
+    let valid = key.len() == 40 && zmq::z85_decode(key).is_ok();
+    if !valid && key.starts_with('#') {
+      continue;
+    }
+    if !valid {
+      warn!("Skip malformed key on line {} of {}", line_no + 1, path.display());
+      continue;
+    }
+    keys.insert(key.to_string());
+  }
+  Ok(keys)
+}
+
+/// Answer ZAP requests for the ROUTER socket, admitting only CURVE clients whose public key is allowlisted.
+/// Must be running before the ROUTER binds, the handler lives as long as `ctx`.
+pub fn spawn_zap_handler(ctx: &zmq::Context, allowlist: HashSet<String>) -> Result<(), Box<dyn std::error::Error>> {
+  let handler = ctx.socket(zmq::REP)?;
+  handler.bind(ZAP_ENDPOINT)?;
+  std::thread::spawn(move ||{
+    debug!("ZAP handler start with {} allowed keys", allowlist.len());
+    loop {
+      let request = match handler.recv_multipart(0) {
+        Ok(_val) => _val,
+        Err(zmq::Error::ETERM) => {debug!("ZAP handler exit");return;},
+        Err(e) => {error!("ZAP handler recv failed: {}", e);continue;}
+      };
+      if request.len() < 6 {
+        error!("Malformed ZAP request with {} frames", request.len());
+        continue;
+      }
+      let client_key = match (request[5].as_slice(), request.get(6)) {
+        (b"CURVE", Some(key)) => zmq::z85_encode(key).ok(),
+        _ => None,
+      };
+      let (status, text, user_id) = match client_key {
+        Some(key) if allowlist.contains(&key) => ("200", "OK", key),
+        Some(key) => {warn!("Refuse CURVE client key {}", key);("400", "Key not allowed", String::new())},
+        None => {warn!("Refuse non CURVE connection");("400", "CURVE required", String::new())},
+      };
+      let reply: [&[u8]; 6] = [b"1.0", &request[1], status.as_bytes(), text.as_bytes(), user_id.as_bytes(), b""];
+      handler.send_multipart(&reply, 0).unwrap_or_else(|e|{error!("ZAP handler send failed: {}", e);});
+    }
+  });
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// Ask the handler the way libzmq does when a client connects, returns the status code and user id.
+  fn zap_request(requester: &zmq::Socket, mechanism: &str, credentials: &[&[u8]]) -> (String, String) {
+    let mut request: Vec<&[u8]> = vec![b"1.0", b"7", ZAP_DOMAIN.as_bytes(), b"127.0.0.1", b"", mechanism.as_bytes()];
+    request.extend_from_slice(credentials);
+    requester.send_multipart(&request, 0).unwrap();
+    let reply = requester.recv_multipart(0).unwrap();
+    assert_eq!(reply.len(), 6);
+    assert_eq!(reply[1], b"7");
+    (String::from_utf8(reply[2].clone()).unwrap(), String::from_utf8(reply[4].clone()).unwrap())
+  }
+
+  #[test]
+  fn zap_admits_only_allowlisted_keys() {
+    let allowed = CurveKeys::generate().unwrap();
+    let stranger = CurveKeys::generate().unwrap();
+    let ctx = zmq::Context::new();
+    spawn_zap_handler(&ctx, HashSet::from([allowed.public_key.clone()])).unwrap();
+    let requester = ctx.socket(zmq::REQ).unwrap();
+    requester.set_rcvtimeo(1000).unwrap();
+    requester.connect(ZAP_ENDPOINT).unwrap();
+
+    let key = |keys: &CurveKeys| zmq::z85_decode(&keys.public_key).unwrap();
+    assert_eq!(zap_request(&requester, "CURVE", &[&key(&allowed)]), ("200".to_string(), allowed.public_key.clone()));
+    assert_eq!(zap_request(&requester, "CURVE", &[&key(&stranger)]), ("400".to_string(), String::new()));
+    assert_eq!(zap_request(&requester, "NULL", &[]).0, "400");
+  }
+
+  #[test]
+  fn allowlist_skips_comments_and_malformed_lines() {
+    let dir = tempfile::tempdir().unwrap();
+    let keys = CurveKeys::generate().unwrap();
+    let hashed = "#".repeat(40);
+    let path = dir.path().join("allowlist.txt");
+    fs::write(&path, format!("# team\n{}  # alice\n\ntooshort\n  {}\n", keys.public_key, hashed)).unwrap();
+    assert_eq!(load_allowlist(&path).unwrap(), HashSet::from([keys.public_key, hashed]));
+  }
+}
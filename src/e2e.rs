@@ -0,0 +1,180 @@
+use crypto_box::{aead::{Aead, AeadCore, OsRng}, Nonce, PublicKey, SalsaBox, SecretKey};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, fs, io::Write, path::{Path, PathBuf}};
+use crate::utils::MessageType;
+
+#[derive(Serialize, Deserialize)]
+struct KeyFile {
+  secret_key: String,
+  /// Peer public keys pinned the first time they were seen.
+  #[serde(default)]
+  peers: BTreeMap<String, String>,
+}
+
+/// The client's own end-to-end keypair plus the peer keys it trusts, kept in `<dir>/<client_id>_e2e.json`.
+pub struct KeyStore {
+  path: PathBuf,
+  secret_key: SecretKey,
+  peers: BTreeMap<String, String>,
+}
+
+fn decode_key(key: &str) -> Result<[u8; 32], String> {
+  zmq::z85_decode(key).ok().and_then(|bytes| bytes.try_into().ok())
+    .ok_or(format!("{} is not a Z85 encoded 32 byte key", key))
+}
+
+fn encode_key(key: &[u8; 32]) -> String {
+  zmq::z85_encode(key).expect("32 bytes always encode")
+}
+
+impl KeyStore {
+  /// Load the key store for `client_id` under `dir`, generating a keypair on first use.
+  pub fn open(dir: &Path, client_id: &str) -> Result<KeyStore, Box<dyn std::error::Error>> {
+    let path = dir.join(format!("{}_e2e.json", client_id));
+    if path.exists() {
+      let file: KeyFile = serde_json::from_slice(&fs::read(&path)?)?;
+      let secret_key = SecretKey::from_bytes(decode_key(&file.secret_key)?);
+      return Ok(KeyStore { path, secret_key, peers: file.peers });
+    }
+    let store = KeyStore { path, secret_key: SecretKey::generate(&mut OsRng), peers: BTreeMap::new() };
+    store.save()?;
+    info!("Generated end-to-end keypair {}, public key {}", store.path.display(), store.public_key());
+    Ok(store)
+  }
+
+  fn save(&self) -> Result<(), Box<dyn std::error::Error>> {
+    let file = KeyFile { secret_key: encode_key(&self.secret_key.to_bytes()), peers: self.peers.clone() };
+    let tmp_path = self.path.with_extension("tmp");
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(&tmp_path)?.write_all(&serde_json::to_vec_pretty(&file)?)?;
+    fs::rename(&tmp_path, &self.path)?;
+    Ok(())
+  }
+
+  pub fn public_key(&self) -> String {
+    encode_key(self.secret_key.public_key().as_bytes())
+  }
+
+  pub fn peer_key(&self, peer: &str) -> Option<&str> {
+    self.peers.get(peer).map(String::as_str)
+  }
+
+  /// Trust on first use: pin `key` for `peer` unless a different key is already pinned.
+  pub fn pin(&mut self, peer: &str, key: &str) -> Result<(), String> {
+    match self.peers.get(peer) {
+      Some(pinned) if pinned == key => return Ok(()),
+      Some(pinned) => return Err(format!("Key of {} changed from {} to {}, run `trust {}` if this is expected", peer, pinned, key, peer)),
+      None => {},
+    }
+    self.replace(peer, key)
+  }
+
+  /// Pin `key` for `peer`, replacing whatever was pinned before.
+  pub fn replace(&mut self, peer: &str, key: &str) -> Result<(), String> {
+    decode_key(key)?;
+    self.peers.insert(peer.to_string(), key.to_string());
+    self.save().map_err(|e| format!("Failed to save {}: {}", self.path.display(), e))
+  }
+
+  pub fn encrypt(&self, peer_key: &str, text: &str) -> Result<MessageType, String> {
+    let peer_box = SalsaBox::new(&PublicKey::from_bytes(decode_key(peer_key)?), &self.secret_key);
+    let nonce = SalsaBox::generate_nonce(&mut OsRng);
+    let ciphertext = peer_box.encrypt(&nonce, text.as_bytes()).map_err(|_| "Encryption failed".to_string())?;
+    Ok(MessageType::EncryptedMsg { sender_key: self.public_key(), nonce: nonce.to_vec(), ciphertext })
+  }
+
+  /// Open a message exchanged with the holder of `peer_key`, whichever side sent it.
+  pub fn decrypt_with(&self, peer_key: &str, nonce: &[u8], ciphertext: &[u8]) -> Result<String, String> {
+    if nonce.len() != 24 {
+      return Err("Malformed nonce".to_string());
+    }
+    let peer_box = SalsaBox::new(&PublicKey::from_bytes(decode_key(peer_key)?), &self.secret_key);
+    let plaintext = peer_box.decrypt(Nonce::from_slice(nonce), ciphertext).map_err(|_| "Decryption failed".to_string())?;
+    String::from_utf8(plaintext).map_err(|_| "Decrypted message is not text".to_string())
+  }
+
+  /// Text of a message received from `sender`. An encrypted one must carry the key pinned for `sender`,
+  /// the first key seen gets pinned.
+  pub fn open_from(&mut self, sender: &str, content: &MessageType) -> Result<String, String> {
+    match content {
+      MessageType::TextMsg { content } => Ok(content.clone()),
+      MessageType::EncryptedMsg { sender_key, nonce, ciphertext } => {
+        if let Err(reason) = self.pin(sender, sender_key) {
+          warn!("{}", reason);
+          return Err(reason);
+        }
+        self.decrypt_with(sender_key, nonce, ciphertext)
+      },
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn stores(dir: &Path) -> (KeyStore, KeyStore) {
+    (KeyStore::open(dir, "alice").unwrap(), KeyStore::open(dir, "bob").unwrap())
+  }
+
+  #[test]
+  fn encrypt_decrypt_round_trip() {
+    let dir = tempfile::tempdir().unwrap();
+    let (alice, mut bob) = stores(dir.path());
+    let sealed = alice.encrypt(&bob.public_key(), "meet at eight").unwrap();
+    assert!(!sealed.to_string().contains("meet"));
+    assert_eq!(bob.open_from("alice", &sealed).unwrap(), "meet at eight");
+    assert_eq!(bob.peer_key("alice"), Some(alice.public_key().as_str()));
+    // The sender reads its own copy back with the recipient's key, as history does
+    let MessageType::EncryptedMsg { nonce, ciphertext, .. } = &sealed else { panic!("expected EncryptedMsg") };
+    assert_eq!(alice.decrypt_with(&bob.public_key(), nonce, ciphertext).unwrap(), "meet at eight");
+
+    let reopened = KeyStore::open(dir.path(), "bob").unwrap();
+    assert_eq!(reopened.public_key(), bob.public_key());
+    assert_eq!(reopened.peer_key("alice"), Some(alice.public_key().as_str()));
+  }
+
+  #[test]
+  fn tampered_messages_are_rejected() {
+    let dir = tempfile::tempdir().unwrap();
+    let (alice, bob) = stores(dir.path());
+    let MessageType::EncryptedMsg { nonce, ciphertext, .. } = alice.encrypt(&bob.public_key(), "pay 10").unwrap() else {
+      panic!("expected EncryptedMsg");
+    };
+    let mut tampered = ciphertext.clone();
+    *tampered.last_mut().unwrap() ^= 1;
+    assert_eq!(bob.decrypt_with(&alice.public_key(), &nonce, &tampered).unwrap_err(), "Decryption failed");
+    let mut wrong_nonce = nonce.clone();
+    wrong_nonce[0] ^= 1;
+    assert_eq!(bob.decrypt_with(&alice.public_key(), &wrong_nonce, &ciphertext).unwrap_err(), "Decryption failed");
+    assert_eq!(bob.decrypt_with(&alice.public_key(), &nonce[..12], &ciphertext).unwrap_err(), "Malformed nonce");
+    let mallory = KeyStore::open(dir.path(), "mallory").unwrap();
+    assert!(bob.decrypt_with(&mallory.public_key(), &nonce, &ciphertext).is_err());
+    assert_eq!(bob.decrypt_with(&alice.public_key(), &nonce, &ciphertext).unwrap(), "pay 10");
+  }
+
+  #[test]
+  fn changed_key_needs_trust() {
+    let dir = tempfile::tempdir().unwrap();
+    let (alice, mut bob) = stores(dir.path());
+    bob.open_from("alice", &alice.encrypt(&bob.public_key(), "first").unwrap()).unwrap();
+
+    // alice lost her key store, or someone else speaks for her
+    fs::remove_file(dir.path().join("alice_e2e.json")).unwrap();
+    let new_alice = KeyStore::open(dir.path(), "alice").unwrap();
+    assert_ne!(new_alice.public_key(), alice.public_key());
+    let sealed = new_alice.encrypt(&bob.public_key(), "second").unwrap();
+    let reason = bob.open_from("alice", &sealed).unwrap_err();
+    assert!(reason.contains("run `trust alice`"), "unexpected reason {}", reason);
+    assert!(bob.pin("alice", &new_alice.public_key()).is_err());
+    assert_eq!(bob.peer_key("alice"), Some(alice.public_key().as_str()));
+
+    bob.replace("alice", &new_alice.public_key()).unwrap();
+    assert_eq!(bob.open_from("alice", &sealed).unwrap(), "second");
+    assert!(bob.open_from("alice", &alice.encrypt(&bob.public_key(), "old key").unwrap()).is_err());
+  }
+}
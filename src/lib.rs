@@ -0,0 +1,14 @@
+//! Protocol types, a client API and an embeddable server for the chat, shared by the `client` and `server` binaries.
+pub mod utils;
+pub mod config;
+pub mod curve;
+pub mod e2e;
+pub mod client;
+#[cfg(feature = "async")]
+pub mod async_zmq;
+pub mod server;
+pub mod auth;
+pub mod store;
+mod queue;
+mod rooms;
+mod receipts;
@@ -0,0 +1,195 @@
+use chrono::{DateTime, Duration, Utc};
+use log::{debug, error, info};
+use serde::{Deserialize, Serialize};
+use std::{collections::{HashMap, VecDeque}, fs, path::{Path, PathBuf}};
+use crate::utils::MessageType;
+
+pub struct QueueConfig {
+  pub max_per_user: usize,
+  pub expiry: Duration,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct QueuedMsg {
+  pub msg_id: u64,
+  pub sender: String,
+  pub content: MessageType,
+  pub time: DateTime<Utc>,
+  queued_at: DateTime<Utc>,
+}
+
+/// Messages held for known accounts that are offline right now.
+/// Saved on every change, so a crash loses nothing that was acknowledged as queued.
+pub struct OfflineQueue {
+  config: QueueConfig,
+  path: PathBuf,
+  queues: HashMap<String, VecDeque<QueuedMsg>>,
+}
+
+impl OfflineQueue {
+  /// Pick up the messages still waiting when the last run ended.
+  pub fn open(config: QueueConfig, path: &Path) -> Result<OfflineQueue, Box<dyn std::error::Error>> {
+    let mut queues: HashMap<String, VecDeque<QueuedMsg>> = HashMap::new();
+    if path.exists() {
+      queues = serde_json::from_slice(&fs::read(path)?)?;
+      info!("Offline queue {} loaded with {} messages", path.display(), queues.values().map(VecDeque::len).sum::<usize>());
+    }
+    Ok(OfflineQueue { config, path: path.to_path_buf(), queues })
+  }
+
+  /// Replace the file with what is waiting now, through a temporary file so a crash mid-write keeps the old one.
+  pub fn save(&mut self) -> Result<(), String> {
+    let targets: Vec<String> = self.queues.keys().cloned().collect();
+    for target in targets {
+      self.drop_expired(&target);
+    }
+    self.queues.retain(|_, queue| !queue.is_empty());
+    if self.queues.is_empty() {
+      return match fs::remove_file(&self.path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(format!("Failed to remove {}: {}", self.path.display(), e)),
+        _ => Ok(()),
+      };
+    }
+    let tmp_path = self.path.with_extension("tmp");
+    let json = serde_json::to_vec(&self.queues).map_err(|e| e.to_string())?;
+    fs::write(&tmp_path, json).and_then(|_| fs::rename(&tmp_path, &self.path))
+      .map_err(|e| format!("Failed to save {}: {}", self.path.display(), e))
+  }
+
+  pub fn push(&mut self, target: &str, msg_id: u64, sender: String, content: MessageType, time: DateTime<Utc>) -> Result<(), String> {
+    self.drop_expired(target);
+    let queue = self.queues.entry(target.to_string()).or_default();
+    if queue.len() >= self.config.max_per_user {
+      return Err(format!("Offline queue of {} is full", target));
+    }
+    queue.push_back(QueuedMsg { msg_id, sender, content, time, queued_at: Utc::now() });
+    debug!("Queued message for {}, {} waiting", target, queue.len());
+    if let Err(e) = self.save() {
+      self.queues.get_mut(target).and_then(VecDeque::pop_back);
+      return Err(e);
+    }
+    Ok(())
+  }
+
+  /// Remove and return the messages still deliverable for `client_id` that `deliverable` accepts, oldest first.
+  /// The others keep waiting.
+  pub fn take(&mut self, client_id: &str, deliverable: impl Fn(&QueuedMsg) -> bool) -> Vec<QueuedMsg> {
+    self.drop_expired(client_id);
+    let Some(queue) = self.queues.get_mut(client_id) else {return Vec::new();};
+    let (taken, kept): (Vec<QueuedMsg>, Vec<QueuedMsg>) = queue.drain(..).partition(|msg| deliverable(msg));
+    *queue = kept.into();
+    if !taken.is_empty() {
+      self.save().unwrap_or_else(|e|{error!("Error {} occured during save offline queue", e)});
+    }
+    taken
+  }
+
+  fn drop_expired(&mut self, client_id: &str) {
+    let expiry = self.config.expiry;
+    if let Some(queue) = self.queues.get_mut(client_id) {
+      let before = queue.len();
+      queue.retain(|msg| Utc::now() - msg.queued_at < expiry);
+      if queue.len() != before {
+        debug!("Dropped {} expired messages for {}", before - queue.len(), client_id);
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn queue_with(path: &Path, expiry: Duration) -> OfflineQueue {
+    OfflineQueue::open(QueueConfig { max_per_user: 3, expiry }, path).unwrap()
+  }
+
+  fn queue(path: &Path) -> OfflineQueue {
+    queue_with(path, Duration::hours(1))
+  }
+
+  fn text(content: &str) -> MessageType {
+    MessageType::TextMsg { content: content.to_string() }
+  }
+
+  #[test]
+  fn survives_a_crash() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("queue.json");
+    let mut crashed = queue(&path);
+    crashed.push("bob", 1, "alice".to_string(), text("first"), Utc::now()).unwrap();
+    crashed.push("carol", 2, "alice".to_string(), text("second"), Utc::now()).unwrap();
+    drop(crashed);
+
+    // Loading leaves the file in place until the messages are delivered
+    let mut reopened = queue(&path);
+    assert!(path.exists());
+    assert_eq!(reopened.take("bob", |_| true)[0].msg_id, 1);
+    assert!(queue(&path).take("bob", |_| true).is_empty());
+    assert_eq!(reopened.take("carol", |_| true)[0].msg_id, 2);
+    assert!(!path.exists());
+    assert!(!path.with_extension("tmp").exists());
+  }
+
+  #[test]
+  fn delivers_oldest_first() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut queue = queue(&dir.path().join("queue.json"));
+    queue.push("bob", 1, "alice".to_string(), text("one"), Utc::now()).unwrap();
+    queue.push("bob", 2, "carol".to_string(), text("two"), Utc::now()).unwrap();
+    queue.push("dave", 3, "alice".to_string(), text("other"), Utc::now()).unwrap();
+    queue.push("bob", 4, "alice".to_string(), text("three"), Utc::now()).unwrap();
+    let taken: Vec<u64> = queue.take("bob", |_| true).iter().map(|msg| msg.msg_id).collect();
+    assert_eq!(taken, [1, 2, 4]);
+    assert!(queue.take("bob", |_| true).is_empty());
+    assert_eq!(queue.take("dave", |_| true).len(), 1);
+  }
+
+  #[test]
+  fn undeliverable_messages_keep_waiting() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("queue.json");
+    let mut waiting = queue(&path);
+    for msg_id in 1..4 {
+      waiting.push("bob", msg_id, "alice".to_string(), text("hi"), Utc::now()).unwrap();
+    }
+    let taken: Vec<u64> = waiting.take("bob", |msg| msg.msg_id != 2).iter().map(|msg| msg.msg_id).collect();
+    assert_eq!(taken, [1, 3]);
+    let held: Vec<u64> = queue(&path).take("bob", |_| true).iter().map(|msg| msg.msg_id).collect();
+    assert_eq!(held, [2]);
+  }
+
+  #[test]
+  fn full_queue_refuses_more() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut queue = queue(&dir.path().join("queue.json"));
+    for msg_id in 0..3 {
+      queue.push("bob", msg_id, "alice".to_string(), text("hi"), Utc::now()).unwrap();
+    }
+    assert_eq!(queue.push("bob", 3, "alice".to_string(), text("hi"), Utc::now()).unwrap_err(), "Offline queue of bob is full");
+    // The cap is per recipient
+    queue.push("carol", 4, "alice".to_string(), text("hi"), Utc::now()).unwrap();
+    assert_eq!(queue.take("bob", |_| true).len(), 3);
+    queue.push("bob", 5, "alice".to_string(), text("hi"), Utc::now()).unwrap();
+  }
+
+  #[test]
+  fn expired_messages_are_dropped() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("queue.json");
+    let mut queue = queue_with(&path, Duration::milliseconds(200));
+    for msg_id in 0..3 {
+      queue.push("bob", msg_id, "alice".to_string(), text("old"), Utc::now()).unwrap();
+    }
+    std::thread::sleep(std::time::Duration::from_millis(300));
+    // Expired messages no longer count against the cap
+    queue.push("bob", 3, "alice".to_string(), text("new"), Utc::now()).unwrap();
+    let taken = queue.take("bob", |_| true);
+    assert_eq!(taken.len(), 1);
+    assert_eq!(taken[0].msg_id, 3);
+
+    queue.push("carol", 4, "alice".to_string(), text("old"), Utc::now()).unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(300));
+    assert!(queue_with(&path, Duration::milliseconds(200)).take("carol", |_| true).is_empty());
+  }
+}
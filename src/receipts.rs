@@ -0,0 +1,70 @@
+use std::collections::{hash_map::Entry, HashMap, VecDeque};
+
+struct Route {
+  sender: String,
+  target: String,
+}
+
+/// Sender and target of delivered messages whose read receipt may still come.
+/// Only the newest `capacity` messages are remembered, older ones can no longer be marked read.
+pub struct ReceiptTracker {
+  capacity: usize,
+  order: VecDeque<u64>,
+  routes: HashMap<u64, Route>,
+}
+
+impl ReceiptTracker {
+  pub fn new(capacity: usize) -> ReceiptTracker {
+    ReceiptTracker { capacity, order: VecDeque::new(), routes: HashMap::new() }
+  }
+
+  pub fn track(&mut self, msg_id: u64, sender: &str, target: &str) {
+    if self.order.len() >= self.capacity {
+      if let Some(oldest) = self.order.pop_front() {
+        self.routes.remove(&oldest);
+      }
+    }
+    self.order.push_back(msg_id);
+    self.routes.insert(msg_id, Route { sender: sender.to_string(), target: target.to_string() });
+  }
+
+  /// Forget `msg_id` once `reader` marks it read and return who sent it. Only the target may mark a message read.
+  pub fn take_read(&mut self, msg_id: u64, reader: &str) -> Result<String, String> {
+    let Entry::Occupied(route) = self.routes.entry(msg_id) else {
+      return Err(format!("No unread message {}", msg_id));
+    };
+    if route.get().target != reader {
+      return Err(format!("No unread message {}", msg_id));
+    }
+    self.order.retain(|id| *id != msg_id);
+    Ok(route.remove().sender)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn only_the_target_marks_read() {
+    let mut receipts = ReceiptTracker::new(10);
+    receipts.track(1, "alice", "bob");
+    assert_eq!(receipts.take_read(1, "alice").unwrap_err(), "No unread message 1");
+    assert!(receipts.take_read(1, "carol").is_err());
+    assert_eq!(receipts.take_read(1, "bob").unwrap(), "alice");
+    assert!(receipts.take_read(1, "bob").is_err());
+    assert!(receipts.take_read(2, "bob").is_err());
+  }
+
+  #[test]
+  fn oldest_are_forgotten_past_capacity() {
+    let mut receipts = ReceiptTracker::new(2);
+    for msg_id in 1..=3 {
+      receipts.track(msg_id, "alice", "bob");
+    }
+    assert!(receipts.take_read(1, "bob").is_err());
+    assert_eq!(receipts.take_read(3, "bob").unwrap(), "alice");
+    assert_eq!(receipts.take_read(2, "bob").unwrap(), "alice");
+    assert!(receipts.order.is_empty());
+  }
+}
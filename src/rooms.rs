@@ -0,0 +1,105 @@
+use std::collections::{btree_map::Entry, BTreeMap, BTreeSet};
+
+pub struct Room {
+  pub owner: String,
+  pub members: BTreeSet<String>,
+}
+
+/// Named chat rooms, each owned by the client that created it.
+#[derive(Default)]
+pub struct Rooms {
+  rooms: BTreeMap<String, Room>,
+}
+
+impl Rooms {
+  pub fn create(&mut self, name: &str, owner: &str) -> Result<(), String> {
+    if name.is_empty() {
+      return Err("Room name must not be empty".to_string());
+    }
+    if self.rooms.contains_key(name) {
+      return Err(format!("Room {} already exists", name));
+    }
+    self.rooms.insert(name.to_string(), Room { owner: owner.to_string(), members: BTreeSet::from([owner.to_string()]) });
+    Ok(())
+  }
+
+  pub fn join(&mut self, name: &str, client_id: &str) -> Result<(), String> {
+    let room = self.rooms.get_mut(name).ok_or(format!("No such room {}", name))?;
+    if !room.members.insert(client_id.to_string()) {
+      return Err(format!("Already in room {}", name));
+    }
+    Ok(())
+  }
+
+  pub fn leave(&mut self, name: &str, client_id: &str) -> Result<(), String> {
+    let room = self.rooms.get_mut(name).ok_or(format!("No such room {}", name))?;
+    if !room.members.remove(client_id) {
+      return Err(format!("Not in room {}", name));
+    }
+    Ok(())
+  }
+
+  /// Only the owner, or an admin, may delete a room. Returns the removed room so its members can be told.
+  pub fn delete(&mut self, name: &str, client_id: &str, is_admin: bool) -> Result<Room, String> {
+    let Entry::Occupied(room) = self.rooms.entry(name.to_string()) else {
+      return Err(format!("No such room {}", name));
+    };
+    if room.get().owner != client_id && !is_admin {
+      return Err(format!("Only {} can delete room {}", room.get().owner, name));
+    }
+    Ok(room.remove())
+  }
+
+  pub fn get(&self, name: &str) -> Result<&Room, String> {
+    self.rooms.get(name).ok_or(format!("No such room {}", name))
+  }
+
+  pub fn names(&self) -> Vec<String> {
+    self.rooms.keys().cloned().collect()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn members(rooms: &Rooms, name: &str) -> Vec<String> {
+    rooms.get(name).unwrap().members.iter().cloned().collect()
+  }
+
+  #[test]
+  fn join_and_leave() {
+    let mut rooms = Rooms::default();
+    rooms.create("dev", "alice").unwrap();
+    assert!(rooms.create("dev", "bob").is_err());
+    assert!(rooms.create("", "bob").is_err());
+    rooms.join("dev", "bob").unwrap();
+    assert_eq!(rooms.join("dev", "bob").unwrap_err(), "Already in room dev");
+    assert_eq!(members(&rooms, "dev"), ["alice", "bob"]);
+    assert_eq!(rooms.join("ops", "bob").unwrap_err(), "No such room ops");
+
+    rooms.leave("dev", "alice").unwrap();
+    assert_eq!(rooms.leave("dev", "alice").unwrap_err(), "Not in room dev");
+    assert_eq!(members(&rooms, "dev"), ["bob"]);
+    // The owner keeps the room after leaving it
+    assert_eq!(rooms.get("dev").unwrap().owner, "alice");
+  }
+
+  #[test]
+  fn only_owner_or_admin_deletes() {
+    let mut rooms = Rooms::default();
+    rooms.create("dev", "alice").unwrap();
+    rooms.create("ops", "alice").unwrap();
+    rooms.join("dev", "bob").unwrap();
+
+    assert_eq!(rooms.delete("dev", "bob", false).err().unwrap(), "Only alice can delete room dev");
+    assert_eq!(rooms.names(), ["dev", "ops"]);
+    let deleted = rooms.delete("dev", "alice", false).unwrap();
+    assert_eq!(deleted.members.into_iter().collect::<Vec<_>>(), ["alice", "bob"]);
+    assert!(rooms.get("dev").is_err());
+    assert!(rooms.delete("dev", "alice", false).is_err());
+
+    rooms.delete("ops", "root", true).unwrap();
+    assert!(rooms.names().is_empty());
+  }
+}
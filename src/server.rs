@@ -1,264 +1,1636 @@
 use chrono::{DateTime, Utc};
-use serde_json;
-use serde::{Deserialize, Serialize};
 use log::{debug, error, info, warn};
-use zmq;
-use std::{collections::HashMap, i8, ptr::eq, sync::{mpsc, Arc, Mutex, OnceLock}};
-mod utils;
-use utils::{ZmqJsonServer, ContactProtocol, input, MsgStatus, NotifyProtocol, MessageType, Protocols};
-
-impl ZmqJsonServer for zmq::Socket {
-  fn recv_json<Protocols: for<'a> Deserialize<'a>>(&self, flags: Option<i32>) -> Result<(String, Protocols), Box<dyn std::error::Error>> {
-    let raw_msgs = self.recv_multipart(flags.unwrap_or(0))?;
-    debug!("id: {:?}", raw_msgs[0]);
-    let client_id = String::from_utf8(raw_msgs[0].to_vec())?;
-    let msg = serde_json::from_slice(&raw_msgs[1])?;
-    Ok((client_id, msg))
-  }
-  fn send_json<Protocols: Serialize>(&self, client_id: &String, data: &Protocols, flags: Option<i32>) -> Result<(), Box<dyn std::error::Error>> {
-    let json_bytes = serde_json::to_vec(data)?;
-    let client_id_bytes = client_id.as_bytes();
-    self.send_multipart(&[&client_id_bytes, &json_bytes], flags.unwrap_or(0))?;
-    Ok(())
-  }
-}
+use std::{collections::{HashMap, HashSet}, thread::JoinHandle, time::{Duration, Instant}};
+use crate::{auth, curve};
+use crate::auth::{Ban, Role, UserDb};
+use crate::config::{ServerConfig, ServerCurveSection};
+use crate::curve::CurveKeys;
+use crate::queue::{OfflineQueue, QueueConfig, QueuedMsg};
+use crate::receipts::ReceiptTracker;
+use crate::rooms::Rooms;
+use crate::store::{FileStore, MessageStore};
+#[cfg(feature = "async")]
+use crate::async_zmq::wait_for;
+use crate::utils::{ZmqJsonServer, decode_frame, encode_legacy_rejection, frame_sender, split_frames, ChatError, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION, Codec, MAX_FRAME_SIZE, ContactProtocol, ClientCommand, ServerCommand, Reply, Availability, Capability, ClientInfo, ClientPresence, Credentials, Handshake, HistoryEntry, MessageType, MsgStatus, NotifyProtocol, Presence, Protocols};
 
 struct Client{
-  state: i8,
+  /// `last_seen` follows `last_heard` while the client is visible and stays put while it is invisible.
+  presence: Presence,
+  /// Time of the last frame received from this client, heartbeats included.
+  last_heard: DateTime<Utc>,
   login_time: DateTime<Utc>,
   client_id: String,
+  handshake: Handshake,
+  role: Role,
+  /// Token handed out at register. Requests must carry it, the routing id alone is chosen by the peer.
+  session: String,
 }
 
-trait ClientMethods {
-  fn respond(&self, socket: &zmq::Socket, state: MsgStatus, command: String, cmd_args: Option<serde_json::Value>) -> Result<(), Box<dyn std::error::Error>>;
-  fn notify(&self, socket: &zmq::Socket, msg: NotifyProtocol) -> Result<(), Box<dyn std::error::Error>>;
+const MAX_HISTORY_PAGE: usize = 100;
+const MAX_STATUS_LEN: usize = 100;
+/// Delivered messages remembered for read receipts.
+const MAX_TRACKED_RECEIPTS: usize = 10_000;
+/// How often the ROUTER loop wakes up to look for clients that stopped sending heartbeats.
+const EVICTION_SWEEP: Duration = Duration::from_secs(1);
+/// Clients without the `Heartbeat` capability are evicted after this many client timeouts without a frame.
+const IDLE_TIMEOUTS: i32 = 10;
+/// The one command a client may send before it is registered, refusing it is a rejection rather than a failure.
+const REGISTER_COMMAND: &str = "register";
+
+/// `curve.keys` enables CURVE with the server keypair at that path, generated on first use.
+/// `curve.allowlist` additionally restricts clients to the public keys listed in that file.
+fn setup_curve(ctx: &zmq::Context, curve_config: &ServerCurveSection) -> Result<Option<CurveKeys>, Box<dyn std::error::Error>> {
+  let Some(keys_path) = &curve_config.keys else {
+    warn!("No CURVE keys configured, traffic is not encrypted");
+    return Ok(None);
+  };
+  let keys = CurveKeys::load_or_generate(keys_path)?;
+  info!("CURVE enabled, server public key {}", keys.public_key);
+  if let Some(allowlist_path) = &curve_config.allowlist {
+    let mut allowlist = curve::load_allowlist(allowlist_path)?;
+    // The shell's control socket connects with the server's own keypair
+    allowlist.insert(keys.public_key.clone());
+    info!("Only {} allowlisted client keys may connect", allowlist.len() - 1);
+    curve::spawn_zap_handler(ctx, allowlist)?;
+  }
+  Ok(Some(keys))
+}
+
+/// Which namespace a command's handler is registered in. Custom command names never reach a built-in's handler.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CommandKind {
+  /// `ClientControl` commands and messages, open to every registered client.
+  Client,
+  /// `ServerControl` commands, only admins get to their handlers.
+  Server,
+  /// `ClientCommand::Custom`, under the name the client calls it by.
+  Custom,
+}
+
+/// A request as handed to its handler.
+pub enum Command {
+  Client(ClientCommand),
+  Server(ServerCommand),
+  /// A direct message, served as `Client` command `message`.
+  Message{target: String, content: MessageType, time: DateTime<Utc>},
+  /// A message to a room, served as `Client` command `room_message`.
+  RoomMessage{room: String, content: MessageType, time: DateTime<Utc>},
+}
+
+impl Command {
+  pub fn kind(&self) -> CommandKind {
+    match self {
+      Command::Client(ClientCommand::Custom { .. }) => CommandKind::Custom,
+      Command::Server(_) => CommandKind::Server,
+      _ => CommandKind::Client,
+    }
+  }
+
+  pub fn name(&self) -> &str {
+    match self {
+      Command::Client(command) => command.name(),
+      Command::Server(command) => command.name(),
+      Command::Message { .. } => "message",
+      Command::RoomMessage { .. } => "room_message",
+    }
+  }
+}
+
+/// Serves one command for the registered client whose id it gets, the result is sent back as ACCEPTED or FAILED.
+pub type CommandHandler = Box<dyn FnMut(&mut ServerContext, &str, Command) -> Result<Reply, String> + Send>;
+
+/// Server state handed to command handlers, owned by the ROUTER thread.
+pub struct ServerContext {
+  socket: zmq::Socket,
+  clients: HashMap<String, Client>,
+  users: UserDb,
+  store: Box<dyn MessageStore + Send>,
+  offline_queue: OfflineQueue,
+  rooms: Rooms,
+  watchers: HashMap<String, HashSet<String>>,
+  offline_presence: HashMap<String, Presence>,
+  receipts: ReceiptTracker,
+  /// Id of the next direct message, counting on from the highest one in the history store.
+  next_msg_id: u64,
+  shutdown_at: Option<Instant>,
+  /// Notifications held back until the response to the command being handled is sent,
+  /// with the codec of their target taken when queued, so a client that just left still gets them.
+  outbox: Vec<(String, Codec, NotifyProtocol)>,
+}
+
+impl ServerContext {
+  /// Ids of the registered clients, sorted.
+  pub fn online_clients(&self) -> Vec<String> {
+    let mut client_ids: Vec<String> = self.clients.keys().cloned().collect();
+    client_ids.sort();
+    client_ids
+  }
+
+  pub fn is_online(&self, client_id: &str) -> bool {
+    self.clients.contains_key(client_id)
+  }
+
+  /// Role of an account, whether it is online or not.
+  pub fn role(&self, client_id: &str) -> Option<Role> {
+    self.clients.get(client_id).map(|client| client.role).or(self.users.role(client_id))
+  }
+
+  /// Push `notification` to an online client that handles notifications, after the current response.
+  pub fn notify(&mut self, client_id: &str, notification: NotifyProtocol) -> Result<(), String> {
+    match self.clients.get(client_id) {
+      Some(client) if client.handshake.supports(Capability::Notifications) => {
+        self.push(client_id, notification);
+        Ok(())
+      },
+      Some(_) => Err(format!("{} cannot receive notifications", client_id)),
+      None => Err(format!("{} is not online", client_id)),
+    }
+  }
+
+  pub fn store(&mut self) -> &mut dyn MessageStore {
+    self.store.as_mut()
+  }
+
+  /// Stop serving once `grace` has passed, persisting state first.
+  pub fn shutdown_in(&mut self, grace: Duration) {
+    self.shutdown_at = Some(Instant::now() + grace);
+  }
+
+  /// What `client_id` agreed on at register, JSON for unregistered peers.
+  fn codec_of(&self, client_id: &str) -> Codec {
+    self.clients.get(client_id).map(|client| client.handshake.codec()).unwrap_or_default()
+  }
+
+  /// Queue `notification` for `client_id` without checking what it understands.
+  fn push(&mut self, client_id: &str, notification: NotifyProtocol) {
+    let codec = self.codec_of(client_id);
+    self.outbox.push((client_id.to_string(), codec, notification));
+  }
+
+  fn supports(&self, client_id: &str, capability: Capability) -> bool {
+    self.clients.get(client_id).is_some_and(|client| client.handshake.supports(capability))
+  }
+
+  fn flush_outbox(&mut self) {
+    for (client_id, codec, notification) in std::mem::take(&mut self.outbox) {
+      self.socket.send_frame(&client_id, &Protocols::NPType(notification), codec, Some(0))
+        .unwrap_or_else(|e|{error!("Error {} occured during notify {}", e, client_id)});
+    }
+  }
+
+  /// Tell the online clients watching `client_id` about its new `presence`. Watchers without the `Presence`
+  /// capability only hear about it when `client_id` appears or disappears compared to `was_visible`.
+  fn notify_presence(&mut self, client_id: &str, presence: &Presence, was_visible: bool) {
+    let Some(watching) = self.watchers.get(client_id) else {return;};
+    let visible = presence.availability != Availability::Offline;
+    let mut notifications = Vec::new();
+    for watcher in watching {
+      let Some(watcher_client) = self.clients.get(watcher) else {continue;};
+      let notification = if watcher_client.handshake.supports(Capability::Presence) {
+        NotifyProtocol::StatusChanged { client_id: client_id.to_string(), presence: shown(presence) }
+      } else if visible != was_visible {
+        NotifyProtocol::PresenceChanged { client_id: client_id.to_string(), online: visible, time: Utc::now() }
+      } else {
+        continue;
+      };
+      notifications.push((watcher.clone(), notification));
+    }
+    for (watcher, notification) in notifications {
+      self.push(&watcher, notification);
+    }
+  }
+
+  /// Tell `sender` how far its message `msg_id` to `target` got, if it is online and understands receipts.
+  fn send_receipt(&mut self, sender: &str, msg_id: u64, target: &str, state: MsgStatus) {
+    if self.supports(sender, Capability::Receipts) {
+      self.push(sender, NotifyProtocol::Receipt { msg_id, from: target.to_string(), state, time: Utc::now() });
+    }
+  }
+
+  /// Drop `client_id`'s registration, telling it why first if it understands.
+  fn kick(&mut self, client_id: &str, reason: &str) -> Result<(), String> {
+    if !self.clients.contains_key(client_id) {
+      return Err(format!("{} is not online", client_id));
+    }
+    if self.supports(client_id, Capability::ServerNotices) {
+      self.push(client_id, NotifyProtocol::Kicked { reason: reason.to_string() });
+    }
+    self.depart(client_id);
+    Ok(())
+  }
+
+  /// Take `client_id` off the online list, remembering its presence as offline for later listings.
+  fn depart(&mut self, client_id: &str) {
+    let Some(client) = self.clients.remove(client_id) else {return;};
+    let was_visible = client.presence.availability != Availability::Offline;
+    let presence = Presence { availability: Availability::Offline, ..client.presence };
+    self.notify_presence(client_id, &presence, was_visible);
+    self.offline_presence.insert(client_id.to_string(), presence);
+  }
+
+  /// Announce a server notice to every online client but `from`: typed to those with `ServerNotices`,
+  /// as a text message from `from` to those that only take notifications.
+  fn announce(&mut self, from: &str, notice: NotifyProtocol, text: &str) {
+    let mut notifications = Vec::new();
+    for client in self.clients.values().filter(|client| client.client_id != from) {
+      let notification = if client.handshake.supports(Capability::ServerNotices) {
+        notice.clone()
+      } else if client.handshake.supports(Capability::Notifications) {
+        NotifyProtocol::MsgFromUser { msg_id: 0, sender: from.to_string(), content: MessageType::TextMsg { content: text.to_string() }, time: Utc::now() }
+      } else {
+        continue;
+      };
+      notifications.push((client.client_id.clone(), notification));
+    }
+    for (client_id, notification) in notifications {
+      self.push(&client_id, notification);
+    }
+  }
 }
 
-impl ClientMethods for Client {
-  fn respond(&self, socket: &zmq::Socket, state: MsgStatus, command: String, cmd_args: Option<serde_json::Value>) -> Result<(), Box<dyn std::error::Error>> {
-    let reponse_msg;
-    reponse_msg = Protocols::CPType(ContactProtocol::ClientControl { state: state, command: command, cmd_args: cmd_args, time: Utc::now() });
-    debug!("Respond to {}", self.client_id);
-    socket.send_json(&self.client_id, &reponse_msg, Some(0))
+fn handle_register(ctx: &mut ServerContext, client_id: &str, command: Command) -> Result<Reply, String> {
+  let Command::Client(ClientCommand::Register { handshake: client_handshake, credentials }) = command else {
+    return Err("Not a register command".to_string());
+  };
+  if ctx.shutdown_at.is_some() {
+    return Err("Server is shutting down".to_string());
   }
-  fn notify(&self, socket: &zmq::Socket, action: NotifyProtocol) -> Result<(), Box<dyn std::error::Error>> {
-    match action {
-      NotifyProtocol::MsgFromUser { ref sender, ref content } => {
-        socket.send_json(&self.client_id, &action, Some(0))
+  let handshake = Handshake::current().negotiate(&client_handshake)?;
+  let (role, token) = ctx.users.authenticate(client_id, &credentials)?;
+  info!("New client connect: {} as {:?} (protocol v{} in {:?}, {:?})", client_id, role, handshake.version, handshake.codec(), handshake.capabilities);
+  // A client reconnecting before its old registration was evicted takes it over and keeps its presence,
+  // otherwise it comes back online with the status text it had when it left
+  let previous = ctx.clients.get(client_id).map(|client| client.presence.clone());
+  let reconnected = previous.is_some();
+  let presence = match previous.or_else(|| ctx.offline_presence.remove(client_id)) {
+    Some(presence) if reconnected && presence.availability == Availability::Offline => presence,
+    Some(presence) if reconnected => Presence { last_seen: Utc::now(), ..presence },
+    Some(presence) => Presence { availability: Availability::Online, last_seen: Utc::now(), ..presence },
+    None => Presence { availability: Availability::Online, status: None, last_seen: Utc::now() },
+  };
+  let this_client = Client {presence: presence.clone(), last_heard: Utc::now(), login_time: Utc::now(), client_id: client_id.to_string(), handshake: handshake.clone(), role, session: token.clone()};
+  ctx.clients.insert(client_id.to_string(), this_client);
+  if reconnected {
+    info!("Client {} registered again, replacing the previous registration", client_id);
+  } else {
+    ctx.notify_presence(client_id, &presence, false);
+  }
+  if handshake.supports(Capability::Notifications) {
+    // Encrypted messages wait for a client that can decrypt them
+    let decrypts = handshake.supports(Capability::Encryption);
+    let deliverable = |queued: &QueuedMsg| decrypts || !matches!(queued.content, MessageType::EncryptedMsg { .. });
+    for queued in ctx.offline_queue.take(client_id, deliverable) {
+      if handshake.supports(Capability::Receipts) {
+        ctx.receipts.track(queued.msg_id, &queued.sender, client_id);
       }
+      ctx.send_receipt(&queued.sender, queued.msg_id, client_id, MsgStatus::DELIVERED);
+      ctx.push(client_id, NotifyProtocol::MsgFromUser { msg_id: queued.msg_id, sender: queued.sender, content: queued.content, time: queued.time });
+    }
+  }
+  Ok(Reply::Registered { handshake, token })
+}
+
+/// `presence` as other clients get to see it: an invisible or departed client shows no status text.
+fn shown(presence: &Presence) -> Presence {
+  match presence.availability {
+    Availability::Offline => Presence { status: None, ..presence.clone() },
+    _ => presence.clone(),
+  }
+}
+
+fn handle_list_clients(ctx: &mut ServerContext, client_id: &str, _command: Command) -> Result<Reply, String> {
+  if ctx.supports(client_id, Capability::Presence) {
+    let mut presences: Vec<ClientPresence> = ctx.clients.values()
+      .map(|client| ClientPresence { client_id: client.client_id.clone(), presence: shown(&client.presence) })
+      .chain(ctx.offline_presence.iter().map(|(id, presence)| ClientPresence { client_id: id.clone(), presence: shown(presence) }))
+      .collect();
+    presences.sort_by(|a, b| a.client_id.cmp(&b.client_id));
+    Ok(Reply::Presences(presences))
+  } else {
+    let mut clients_vec = Vec::<String>::new();
+    for client in ctx.clients.values().filter(|client| client.presence.availability != Availability::Offline){
+      clients_vec.push(client.client_id.clone());
+    }
+    Ok(Reply::Clients(clients_vec))
+  }
+}
+
+fn handle_unregister(ctx: &mut ServerContext, client_id: &str, _command: Command) -> Result<Reply, String> {
+  ctx.depart(client_id);
+  info!("Client {} gone", client_id);
+  Ok(Reply::Done)
+}
+
+fn handle_heartbeat(_ctx: &mut ServerContext, _client_id: &str, _command: Command) -> Result<Reply, String> {
+  Ok(Reply::Done)
+}
+
+fn handle_set_presence(ctx: &mut ServerContext, client_id: &str, command: Command) -> Result<Reply, String> {
+  let Command::Client(ClientCommand::SetPresence { availability, status }) = command else {
+    return Err("Not a set_presence command".to_string());
+  };
+  if status.as_ref().is_some_and(|status| status.chars().count() > MAX_STATUS_LEN) {
+    return Err(format!("Status text is limited to {} characters", MAX_STATUS_LEN));
+  }
+  let changed_client = ctx.clients.get_mut(client_id).ok_or("Not registered")?;
+  let was_visible = changed_client.presence.availability != Availability::Offline;
+  if availability != Availability::Offline {
+    changed_client.presence.last_seen = Utc::now();
+  }
+  changed_client.presence.availability = availability;
+  changed_client.presence.status = status;
+  let presence = changed_client.presence.clone();
+  info!("Client {} is now {}", client_id, presence);
+  ctx.notify_presence(client_id, &presence, was_visible);
+  Ok(Reply::Done)
+}
+
+fn handle_watch(ctx: &mut ServerContext, client_id: &str, command: Command) -> Result<Reply, String> {
+  let Command::Client(ClientCommand::Watch { client_id: watched }) = command else {
+    return Err("Not a watch command".to_string());
+  };
+  if !ctx.users.contains(&watched) {
+    return Err(format!("No account {}", watched));
+  }
+  ctx.watchers.entry(watched).or_default().insert(client_id.to_string());
+  Ok(Reply::Done)
+}
+
+fn handle_unwatch(ctx: &mut ServerContext, client_id: &str, command: Command) -> Result<Reply, String> {
+  let Command::Client(ClientCommand::Unwatch { client_id: watched }) = command else {
+    return Err("Not an unwatch command".to_string());
+  };
+  if let Some(watching) = ctx.watchers.get_mut(&watched) {
+    watching.remove(client_id);
+  }
+  Ok(Reply::Done)
+}
+
+fn handle_history(ctx: &mut ServerContext, client_id: &str, command: Command) -> Result<Reply, String> {
+  let Command::Client(ClientCommand::History { peer, offset, limit }) = command else {
+    return Err("Not a history command".to_string());
+  };
+  let (messages, total) = ctx.store.query(client_id, peer.as_deref(), offset, limit.min(MAX_HISTORY_PAGE));
+  Ok(Reply::History { messages, total })
+}
+
+fn handle_create_room(ctx: &mut ServerContext, client_id: &str, command: Command) -> Result<Reply, String> {
+  let Command::Client(ClientCommand::CreateRoom { room }) = command else {
+    return Err("Not a create_room command".to_string());
+  };
+  ctx.rooms.create(&room, client_id).map(|_| Reply::Done)
+}
+
+fn handle_join_room(ctx: &mut ServerContext, client_id: &str, command: Command) -> Result<Reply, String> {
+  let Command::Client(ClientCommand::JoinRoom { room }) = command else {
+    return Err("Not a join_room command".to_string());
+  };
+  ctx.rooms.join(&room, client_id).map(|_| Reply::Done)
+}
+
+fn handle_leave_room(ctx: &mut ServerContext, client_id: &str, command: Command) -> Result<Reply, String> {
+  let Command::Client(ClientCommand::LeaveRoom { room }) = command else {
+    return Err("Not a leave_room command".to_string());
+  };
+  ctx.rooms.leave(&room, client_id).map(|_| Reply::Done)
+}
+
+fn handle_delete_room(ctx: &mut ServerContext, client_id: &str, command: Command) -> Result<Reply, String> {
+  let Command::Client(ClientCommand::DeleteRoom { room }) = command else {
+    return Err("Not a delete_room command".to_string());
+  };
+  let is_admin = ctx.role(client_id) == Some(Role::Admin);
+  let deleted = ctx.rooms.delete(&room, client_id, is_admin)?;
+  for member in deleted.members.iter().filter(|member| *member != client_id) {
+    if ctx.supports(member, Capability::Rooms) {
+      ctx.push(member, NotifyProtocol::RoomDeleted { room: room.clone(), by: client_id.to_string() });
     }
-    
   }
+  info!("Room {} deleted by {}", room, client_id);
+  Ok(Reply::Done)
+}
+
+fn handle_room_members(ctx: &mut ServerContext, _client_id: &str, command: Command) -> Result<Reply, String> {
+  let Command::Client(ClientCommand::RoomMembers { room }) = command else {
+    return Err("Not a room_members command".to_string());
+  };
+  let this_room = ctx.rooms.get(&room)?;
+  Ok(Reply::Members { owner: this_room.owner.clone(), members: this_room.members.iter().cloned().collect(), room })
+}
+
+fn handle_list_rooms(ctx: &mut ServerContext, _client_id: &str, _command: Command) -> Result<Reply, String> {
+  Ok(Reply::Rooms(ctx.rooms.names()))
+}
+
+fn handle_publish_key(ctx: &mut ServerContext, client_id: &str, command: Command) -> Result<Reply, String> {
+  let Command::Client(ClientCommand::PublishKey { public_key }) = command else {
+    return Err("Not a publish_key command".to_string());
+  };
+  match zmq::z85_decode(&public_key) {
+    Ok(key) if key.len() == 32 => {},
+    _ => return Err("Public key must be 32 bytes in Z85".to_string()),
+  }
+  ctx.users.set_public_key(client_id, public_key.clone())?;
+  info!("Client {} published public key {}", client_id, public_key);
+  Ok(Reply::Done)
+}
+
+fn handle_fetch_key(ctx: &mut ServerContext, _client_id: &str, command: Command) -> Result<Reply, String> {
+  let Command::Client(ClientCommand::FetchKey { client_id: owner }) = command else {
+    return Err("Not a fetch_key command".to_string());
+  };
+  match ctx.users.public_key(&owner) {
+    Some(public_key) => Ok(Reply::PublicKey { public_key: public_key.to_string(), client_id: owner }),
+    None => Err(format!("{} has not published a public key", owner)),
+  }
+}
+
+fn handle_mark_read(ctx: &mut ServerContext, client_id: &str, command: Command) -> Result<Reply, String> {
+  let Command::Client(ClientCommand::MarkRead { msg_id }) = command else {
+    return Err("Not a mark_read command".to_string());
+  };
+  let sender = ctx.receipts.take_read(msg_id, client_id)?;
+  ctx.send_receipt(&sender, msg_id, client_id, MsgStatus::READ);
+  Ok(Reply::Done)
+}
+
+fn handle_message(ctx: &mut ServerContext, client_id: &str, command: Command) -> Result<Reply, String> {
+  let Command::Message { target, content, time } = command else {
+    return Err("Not a direct message".to_string());
+  };
+  let msg_id = ctx.next_msg_id;
+  ctx.next_msg_id += 1;
+  let entry = HistoryEntry { msg_id, sender: client_id.to_string(), target: target.clone(), content: content.clone(), time };
+  let receipts_wanted = ctx.supports(client_id, Capability::Receipts);
+  let Some(target_client) = ctx.clients.get(&target) else {
+    if !ctx.users.contains(&target) {
+      return Err("No such target".to_string());
+    }
+    ctx.offline_queue.push(&target, msg_id, client_id.to_string(), content, time)?;
+    ctx.store.append(entry).unwrap_or_else(|e|{error!("Error {} occured during record message from {}", e, client_id)});
+    return Ok(if receipts_wanted {Reply::Sent { msg_id, queued: true }} else {Reply::Queued});
+  };
+  if !target_client.handshake.supports(Capability::Notifications) {
+    return Err("Target client cannot receive messages".to_string());
+  }
+  if matches!(content, MessageType::EncryptedMsg { .. }) && !target_client.handshake.supports(Capability::Encryption) {
+    return Err("Target client cannot decrypt messages".to_string());
+  }
+  let target_receipts = target_client.handshake.supports(Capability::Receipts);
+  ctx.push(&target, NotifyProtocol::MsgFromUser { msg_id, sender: client_id.to_string(), content, time });
+  ctx.store.append(entry).unwrap_or_else(|e|{error!("Error {} occured during record message from {}", e, client_id)});
+  if target_receipts {
+    ctx.receipts.track(msg_id, client_id, &target);
+  }
+  ctx.send_receipt(client_id, msg_id, &target, MsgStatus::DELIVERED);
+  Ok(if receipts_wanted {Reply::Sent { msg_id, queued: false }} else {Reply::Done})
+}
+
+fn handle_room_message(ctx: &mut ServerContext, client_id: &str, command: Command) -> Result<Reply, String> {
+  let Command::RoomMessage { room, content, time } = command else {
+    return Err("Not a room message".to_string());
+  };
+  let this_room = ctx.rooms.get(&room)?;
+  if !this_room.members.contains(client_id) {
+    return Err(format!("Not in room {}", room));
+  }
+  let members: Vec<String> = this_room.members.iter().filter(|member| *member != client_id).cloned().collect();
+  for member in members {
+    if ctx.supports(&member, Capability::Rooms) {
+      ctx.push(&member, NotifyProtocol::MsgFromRoom { room: room.clone(), sender: client_id.to_string(), content: content.clone(), time });
+    }
+  }
+  Ok(Reply::Done)
+}
+
+fn handle_shutdown(ctx: &mut ServerContext, client_id: &str, command: Command) -> Result<Reply, String> {
+  let Command::Server(ServerCommand::Shutdown { reason, grace_secs }) = command else {
+    return Err("Not a shutdown command".to_string());
+  };
+  info!("Shutdown cmd received from {}, stopping in {}s", client_id, grace_secs);
+  let text = match &reason {
+    Some(reason) => format!("Server shutting down in {}s: {}", grace_secs, reason),
+    None => format!("Server shutting down in {}s", grace_secs),
+  };
+  ctx.announce(client_id, NotifyProtocol::ServerShutdown { reason, grace_secs, time: Utc::now() }, &text);
+  ctx.shutdown_in(Duration::from_secs(grace_secs));
+  Ok(Reply::Done)
+}
+
+fn handle_add_user(ctx: &mut ServerContext, _client_id: &str, command: Command) -> Result<Reply, String> {
+  let Command::Server(ServerCommand::AddUser { client_id: new_id, password, admin }) = command else {
+    return Err("Not an add_user command".to_string());
+  };
+  let Credentials::Password(password) = password else {
+    return Err("AddUser needs a password".to_string());
+  };
+  let role = if admin {Role::Admin} else {Role::User};
+  ctx.users.add_user(&new_id, &password, role)?;
+  info!("Account {} added as {:?}", new_id, role);
+  Ok(Reply::Done)
+}
+
+fn handle_remove_user(ctx: &mut ServerContext, _client_id: &str, command: Command) -> Result<Reply, String> {
+  let Command::Server(ServerCommand::RemoveUser { client_id: old_id }) = command else {
+    return Err("Not a remove_user command".to_string());
+  };
+  ctx.users.remove_user(&old_id)?;
+  info!("Account {} removed", old_id);
+  Ok(Reply::Done)
+}
+
+fn handle_kick(ctx: &mut ServerContext, client_id: &str, command: Command) -> Result<Reply, String> {
+  let Command::Server(ServerCommand::Kick { client_id: kicked, reason }) = command else {
+    return Err("Not a kick command".to_string());
+  };
+  if kicked == client_id {
+    return Err("Cannot kick yourself".to_string());
+  }
+  let reason = reason.unwrap_or(format!("Kicked by {}", client_id));
+  ctx.kick(&kicked, &reason)?;
+  info!("Client {} kicked by {}: {}", kicked, client_id, reason);
+  Ok(Reply::Done)
 }
 
-static CLIENTS: OnceLock<Mutex<HashMap<String, Client>>> = OnceLock::new();
+fn handle_ban(ctx: &mut ServerContext, client_id: &str, command: Command) -> Result<Reply, String> {
+  let Command::Server(ServerCommand::Ban { client_id: banned, until, reason }) = command else {
+    return Err("Not a ban command".to_string());
+  };
+  if banned == client_id {
+    return Err("Cannot ban yourself".to_string());
+  }
+  let ban = Ban { until, reason };
+  ctx.users.ban(&banned, ban.clone())?;
+  info!("Account {} banned by {}: {}", banned, client_id, ban);
+  if ctx.is_online(&banned) {
+    ctx.kick(&banned, &ban.to_string()).unwrap_or_else(|reason|{warn!("{}", reason)});
+  }
+  Ok(Reply::Done)
+}
 
-fn get_clients() -> &'static Mutex<HashMap<String, Client>> {
-  CLIENTS.get_or_init(|| Mutex::new(HashMap::new()))
+fn handle_unban(ctx: &mut ServerContext, client_id: &str, command: Command) -> Result<Reply, String> {
+  let Command::Server(ServerCommand::Unban { client_id: banned }) = command else {
+    return Err("Not an unban command".to_string());
+  };
+  ctx.users.unban(&banned)?;
+  info!("Account {} unbanned by {}", banned, client_id);
+  Ok(Reply::Done)
 }
 
-fn main(){
-  env_logger::init();
-  let zmq_ctx = Arc::new(zmq::Context::new());
-  let ctx_for_router = Arc::clone(&zmq_ctx);
-  let (thread_sender, main_receiver) = mpsc::channel();
-  get_clients().lock().unwrap().insert("root".to_string(), Client {state: 0, login_time: Utc::now(), client_id: "root".to_string()});
-  let router_handle = std::thread::spawn(move ||{
-    debug!("Child thread with ROUTER start");
-    let socket;
-    match ctx_for_router.socket(zmq::ROUTER) {
-      Ok(_val) => {socket = _val;debug!("ROUTER socket created");},
-      Err(e) => {error!("Failed to create socket: {}", e.to_string());let _=thread_sender.send("init failed");return;}
+fn handle_broadcast(ctx: &mut ServerContext, client_id: &str, command: Command) -> Result<Reply, String> {
+  let Command::Server(ServerCommand::Broadcast { content }) = command else {
+    return Err("Not a broadcast command".to_string());
+  };
+  ctx.announce(client_id, NotifyProtocol::Broadcast { sender: client_id.to_string(), content: content.clone(), time: Utc::now() }, &content);
+  info!("{} broadcast: {}", client_id, content);
+  Ok(Reply::Done)
+}
+
+fn handle_info(ctx: &mut ServerContext, _client_id: &str, command: Command) -> Result<Reply, String> {
+  let Command::Server(ServerCommand::Info { client_id: about }) = command else {
+    return Err("Not an info command".to_string());
+  };
+  let online = ctx.clients.get(&about);
+  let Some(role) = ctx.role(&about) else {
+    return Err(format!("No account {}", about));
+  };
+  Ok(Reply::ClientInfo(ClientInfo {
+    admin: Some(role == Role::Admin),
+    presence: online.map(|client| &client.presence).or(ctx.offline_presence.get(&about)).map(shown),
+    login_time: online.map(|client| client.login_time),
+    handshake: online.map(|client| client.handshake.clone()),
+    ban: ctx.users.active_ban(&about).map(|ban| ban.to_string()),
+    client_id: about,
+  }))
+}
+
+/// Built-in handlers, registered by `ChatServer::new` under these names.
+type BuiltinHandler = fn(&mut ServerContext, &str, Command) -> Result<Reply, String>;
+
+const CLIENT_HANDLERS: [(&str, BuiltinHandler); 19] = [
+  (REGISTER_COMMAND, handle_register),
+  ("get_clients", handle_list_clients),
+  ("unregister", handle_unregister),
+  ("heartbeat", handle_heartbeat),
+  ("set_presence", handle_set_presence),
+  ("watch", handle_watch),
+  ("unwatch", handle_unwatch),
+  ("history", handle_history),
+  ("create_room", handle_create_room),
+  ("join_room", handle_join_room),
+  ("leave_room", handle_leave_room),
+  ("delete_room", handle_delete_room),
+  ("room_members", handle_room_members),
+  ("list_rooms", handle_list_rooms),
+  ("publish_key", handle_publish_key),
+  ("fetch_key", handle_fetch_key),
+  ("mark_read", handle_mark_read),
+  ("message", handle_message),
+  ("room_message", handle_room_message),
+];
+
+const SERVER_HANDLERS: [(&str, BuiltinHandler); 8] = [
+  ("shutdown", handle_shutdown),
+  ("add_user", handle_add_user),
+  ("remove_user", handle_remove_user),
+  ("kick", handle_kick),
+  ("ban", handle_ban),
+  ("unban", handle_unban),
+  ("broadcast", handle_broadcast),
+  ("info", handle_info),
+];
+
+/// A chat server that is configured but not serving yet. Commands are served by the handler registered under
+/// their kind and name. Every `Client` and `Server` command comes built in and can be replaced.
+pub struct ChatServer {
+  config: ServerConfig,
+  zmq_ctx: zmq::Context,
+  curve_keys: Option<CurveKeys>,
+  root_token: String,
+  handlers: HashMap<(CommandKind, String), CommandHandler>,
+}
+
+impl ChatServer {
+  pub fn new(config: ServerConfig) -> Result<ChatServer, Box<dyn std::error::Error>> {
+    let zmq_ctx = zmq::Context::new();
+    let curve_keys = setup_curve(&zmq_ctx, &config.curve).map_err(|e| format!("Failed to set up CURVE: {}", e))?;
+    let mut server = ChatServer { config, zmq_ctx, curve_keys, root_token: auth::new_token(), handlers: HashMap::new() };
+    for (name, handler) in CLIENT_HANDLERS {
+      server.register_handler(CommandKind::Client, name, handler);
     }
-    match socket.bind("tcp://*:23") {
-      Ok(_val) => {debug!("Bind ok");},
-      Err(e) => {error!("Failed to bind to 5555: {}", e.to_string());let _=thread_sender.send("init failed");return;}
+    for (name, handler) in SERVER_HANDLERS {
+      server.register_handler(CommandKind::Server, name, handler);
     }
+    Ok(server)
+  }
+
+  /// Serve the `kind` command named `name` with `handler`, replacing whatever served it before.
+  /// Custom commands arrive as `ClientCommand::Custom` and are registered as `CommandKind::Custom` under their own name.
+  pub fn register_handler<F>(&mut self, kind: CommandKind, name: &str, handler: F)
+  where F: FnMut(&mut ServerContext, &str, Command) -> Result<Reply, String> + Send + 'static {
+    self.handlers.insert((kind, name.to_string()), Box::new(handler));
+  }
+
+  /// The context the server's sockets are created in, clients connecting over `inproc` must share it.
+  pub fn zmq_context(&self) -> &zmq::Context {
+    &self.zmq_ctx
+  }
+
+  /// The server's CURVE keypair when CURVE is enabled.
+  pub fn curve_keys(&self) -> Option<&CurveKeys> {
+    self.curve_keys.as_ref()
+  }
+
+  /// Session token `root` registers with, it is only valid while this server runs.
+  pub fn root_token(&self) -> &str {
+    &self.root_token
+  }
+
+  /// Bind and open the stores, then serve on a new thread until shut down.
+  pub fn start(self) -> Result<JoinHandle<()>, Box<dyn std::error::Error>> {
+    let router = self.open()?;
+    Ok(std::thread::spawn(move ||{router.run()}))
+  }
+
+  /// Bind and open the stores, then serve on this thread until shut down.
+  pub fn run(self) -> Result<(), Box<dyn std::error::Error>> {
+    self.open()?.run();
+    Ok(())
+  }
+
+  /// Bind and open the stores, then serve on the current Tokio runtime until shut down.
+  /// Handlers run on the thread polling this future and a password `register` spends a while in argon2,
+  /// so give it a thread of its own, e.g. a current-thread runtime inside `spawn_blocking`.
+  #[cfg(feature = "async")]
+  pub async fn serve(self) -> Result<(), Box<dyn std::error::Error>> {
+    self.open()?.serve().await?;
+    Ok(())
+  }
+
+  fn open(self) -> Result<Router, Box<dyn std::error::Error>> {
+    let config = &self.config;
+    let socket = self.zmq_ctx.socket(zmq::ROUTER).map_err(|e| format!("Failed to create socket: {}", e))?;
+    if let Some(keys) = &self.curve_keys {
+      keys.apply_server(&socket).map_err(|e| format!("Failed to enable CURVE: {}", e))?;
+    }
+    socket.set_rcvtimeo(EVICTION_SWEEP.as_millis() as i32).map_err(|e| format!("Failed to set recv timeout: {}", e))?;
+    socket.set_maxmsgsize(2 * MAX_FRAME_SIZE as i64).map_err(|e| format!("Failed to set max message size: {}", e))?;
+    socket.bind(&config.bind).map_err(|e| format!("Failed to bind to {}: {}", config.bind, e))?;
+    info!("Bound to {}", config.bind);
+    let store = FileStore::open(&config.history_path)
+      .map_err(|e| format!("Failed to open history store {}: {}", config.history_path.display(), e))?;
+    let mut users = UserDb::open(&config.users_path)
+      .map_err(|e| format!("Failed to open user database {}: {}", config.users_path.display(), e))?;
+    users.grant_session("root", Role::Admin, self.root_token.clone());
+    let queue_config = QueueConfig { max_per_user: config.queue.max_per_user, expiry: chrono::Duration::seconds(config.queue.expiry_secs) };
+    let offline_queue = OfflineQueue::open(queue_config, &config.queue.path)
+      .map_err(|e| format!("Failed to load offline queue {}: {}", config.queue.path.display(), e))?;
+    let next_msg_id = store.last_msg_id() + 1;
+    let ctx = ServerContext {
+      socket,
+      clients: HashMap::new(),
+      users,
+      store: Box::new(store),
+      offline_queue,
+      rooms: Rooms::default(),
+      watchers: HashMap::new(),
+      offline_presence: HashMap::new(),
+      receipts: ReceiptTracker::new(MAX_TRACKED_RECEIPTS),
+      next_msg_id,
+      shutdown_at: None,
+      outbox: Vec::new(),
+    };
     info!("Listening thread ok");
-    let _=thread_sender.send("init ok");
+    Ok(Router { ctx, handlers: self.handlers, client_timeout: chrono::Duration::seconds(config.client_timeout_secs as i64), last_sweep: Instant::now() })
+  }
+}
+
+/// The serving half of a `ChatServer`, owned by the ROUTER thread.
+struct Router {
+  ctx: ServerContext,
+  handlers: HashMap<(CommandKind, String), CommandHandler>,
+  client_timeout: chrono::Duration,
+  last_sweep: Instant,
+}
+
+impl Router {
+  fn run(mut self) {
     loop {
-      let raw_msg;
-      let client_id: String;
-      match socket.recv_json(Some(0)) {
-        Ok(_val) => {(client_id, raw_msg) = _val;debug!("Msg received");},
-        Err(e) => {
-          if let Some(zmq_e) = e.downcast_ref::<zmq::Error>() {
-            error!("Zmq err occured: {}", zmq_e.to_string());
-          }else if let Some(json_e) = e.downcast_ref::<serde_json::Error>(){
-            error!("Json err occured: {}", json_e.to_string());
-          }
-          else if let Some(str_e) = e.downcast_ref::<std::string::FromUtf8Error>(){
-            error!("String err occured: {}", str_e.to_string());
-          }
-          else {
-            error!("Unknow err occured: {}", e.to_string());
-          }
-          continue;
-        }
+      if self.shutdown_due() {
+        break;
       }
-      if let Protocols::CPType(ContactProtocol::ClientControl { ref state, ref command, ref cmd_args, ref time }) = raw_msg{
-        if command == "register"{
-          if let Some(client) = get_clients().lock().unwrap().get(&client_id){
-            warn!("Client {} has registered, reject another registry", client_id);
-            client.respond(&socket, MsgStatus::REJECTED, "Multiple registry".to_string(), None)
-              .unwrap_or_else(|e|{warn!("Error occured during respond to client: {}", e.to_string());});
-            continue;
-          }
-          info!("New client connect: {}", client_id);
-          let mut clients_lock = get_clients().lock().unwrap();
-          let this_client = Client {state: 0, login_time: Utc::now(), client_id: client_id.clone()};
-          clients_lock.insert(client_id.clone(), this_client);
-          clients_lock.get(&client_id).unwrap().respond(&socket, MsgStatus::ACCEPTED, command.clone(), cmd_args.clone())
-            .unwrap_or_else(|e|{error!("Error occured during confirm register: {}", e.to_string());});
-          continue;
+      self.sweep();
+      if let Some((client_id, raw_msg)) = self.recv(0) {
+        self.handle(client_id, raw_msg);
+      }
+    }
+  }
+
+  /// Serve on the current Tokio runtime, waking up on the socket's `ZMQ_FD` instead of a receive timeout.
+  #[cfg(feature = "async")]
+  async fn serve(mut self) -> std::io::Result<()> {
+    let fd = tokio::io::unix::AsyncFd::new(self.ctx.socket.get_fd()?)?;
+    loop {
+      if self.shutdown_due() {
+        break;
+      }
+      self.sweep();
+      match tokio::time::timeout(EVICTION_SWEEP, wait_for(&fd, &mut self.ctx.socket, zmq::POLLIN)).await {
+        Ok(readable) => {readable?;},
+        Err(_) => {continue;}
+      }
+      if let Some((client_id, raw_msg)) = self.recv(zmq::DONTWAIT) {
+        self.handle(client_id, raw_msg);
+      }
+    }
+    Ok(())
+  }
+
+  /// Persist the stores once the shutdown grace period is over, true when serving should stop.
+  fn shutdown_due(&mut self) -> bool {
+    if self.ctx.shutdown_at.is_none_or(|at| Instant::now() < at) {
+      return false;
+    }
+    info!("Grace period over, quiting...");
+    self.ctx.store.flush().unwrap_or_else(|e|{error!("Error {} occured during flush history store", e)});
+    self.ctx.offline_queue.save().unwrap_or_else(|e|{error!("Error {} occured during save offline queue", e)});
+    true
+  }
+
+  /// Evict the clients that stopped sending heartbeats, and those that never send any once they have been idle
+  /// for `IDLE_TIMEOUTS` client timeouts, at most once per `EVICTION_SWEEP`.
+  fn sweep(&mut self) {
+    if self.last_sweep.elapsed() < EVICTION_SWEEP {
+      return;
+    }
+    self.last_sweep = Instant::now();
+    let heartbeat_deadline = Utc::now() - self.client_timeout;
+    let idle_deadline = Utc::now() - self.client_timeout * IDLE_TIMEOUTS;
+    let stale: Vec<String> = self.ctx.clients.values()
+      .filter(|client| {
+        let deadline = if client.handshake.supports(Capability::Heartbeat) {heartbeat_deadline} else {idle_deadline};
+        client.last_heard < deadline
+      })
+      .map(|client| client.client_id.clone()).collect();
+    for stale_id in stale {
+      warn!("Client {} missed heartbeats, evicted", stale_id);
+      self.ctx.depart(&stale_id);
+    }
+    self.ctx.flush_outbox();
+  }
+
+  /// The next frame, or None when there was none or it could not be read. A peer whose payload is malformed
+  /// or does not decode is told why, as long as its identity is usable.
+  fn recv(&mut self, flags: i32) -> Option<(String, Protocols)> {
+    let frames = match self.ctx.socket.recv_multipart(flags).map_err(ChatError::from) {
+      Ok(_val) => _val,
+      Err(e) if e.is_timeout() => {return None;},
+      Err(e) => {error!("Failed to receive: {}", e);return None;}
+    };
+    let sender = frame_sender(&frames);
+    let (client_id, raw_msg) = match split_frames(frames) {
+      Ok(_val) => _val,
+      Err(e) => {
+        warn!("Message dropped, {}", e);
+        if let Some(client_id) = sender {
+          self.reply_error(&client_id, 0, MsgStatus::FAILED, e);
         }
+        return None;
       }
-      if !get_clients().lock().unwrap().contains_key(&client_id){
-        warn!("Not registered client: {}", client_id);
-        let reject_msg = ContactProtocol::ClientControl { state: MsgStatus::REJECTED, command: "register".to_string(), cmd_args: None, time: Utc::now() };
-        socket.send_json(&client_id, &reject_msg, Some(0))
-          .unwrap_or_else(|e|{error!("Error {} occured during reject unregistered client {}", e.to_string(), client_id);});
+    };
+    match decode_frame(&raw_msg) {
+      Ok(msg) => {debug!("Msg received");Some((client_id, msg))},
+      Err(e) => {
+        warn!("Frame from {} dropped, {}", client_id, e);
+        self.reply_error(&client_id, 0, MsgStatus::FAILED, e);
+        None
       }
-      match raw_msg{
-        Protocols::CPType(ContactProtocol::ServerControl { state, command, cmd_args, time })=> {
-          debug!("Receive server cmd from {}", client_id);
-          if client_id != "root" {
-            warn!("Client {} try to use server cmd", client_id);
-            continue;
-          }
-          match command.as_str() {
-            "shutdown" => {
-              info!("Shutdown cmd received, quiting...");
-              break;
-            },
-            _ => {
-              warn!("invalid server cmd");
-              continue;
-            }
-          }
-        },
-        Protocols::CPType(ContactProtocol::ClientControl { state, command, cmd_args, time }) => {
-          debug!("ClientControl command {} has been requested", command);
-          let mut clients_lock = get_clients().lock().unwrap();
-          debug!("ClientControl get lock");
-          let this_client = clients_lock.get(client_id.as_str()).unwrap();
-          let respond_failed_callback = 
-            |e: Box<dyn std::error::Error>, cmd: String|{error!("Error {} occured during responding {} for {}", e.to_string(), client_id, cmd);};
-          match command.as_str() {
-            "get_clients" => {
-              let mut clients_vec = Vec::<String>::new();
-              for client in clients_lock.iter(){
-                clients_vec.push(client.0.to_string());
-              }
-              let clients_json = serde_json::to_value(clients_vec).unwrap();
-              this_client.respond(&socket, MsgStatus::ACCEPTED, "get_clients".to_string(), Some(clients_json))
-                .unwrap_or_else(|e|{respond_failed_callback(e, command);});
-            }
-            "unregister" => {
-              clients_lock.remove(&client_id).unwrap();
-              info!("Client {} gone", client_id);
-            }
-            _ => {warn!("Client {} send invalid client cmd", client_id);}
+    }
+  }
+
+  fn reply_error(&self, client_id: &str, req_id: u64, state: MsgStatus, error: ChatError) {
+    let handshake = self.ctx.clients.get(client_id).map(|client| &client.handshake);
+    let error_msg = Protocols::CPType(ContactProtocol::Response { req_id, state, reply: error.to_reply(handshake), time: Utc::now() });
+    self.ctx.socket.send_frame(client_id, &error_msg, self.ctx.codec_of(client_id), Some(0))
+      .unwrap_or_else(|e|{error!("Error {} occured during report {} to {}", e, error, client_id)});
+  }
+
+  /// Turn away a client registering with a protocol older than `MIN_PROTOCOL_VERSION`, in the frame it still understands.
+  fn reject_legacy(&self, client_id: &str, version: u32) {
+    let reason = format!("Protocol v{} is no longer supported, upgrade to a client speaking v{}", version, PROTOCOL_VERSION);
+    warn!("Reject client {}: {}", client_id, reason);
+    match encode_legacy_rejection(&reason) {
+      Ok(frame) => {
+        self.ctx.socket.send_multipart(&[client_id.as_bytes(), &frame], 0)
+          .unwrap_or_else(|e|{error!("Error {} occured during reject legacy client {}", e, client_id)});
+      },
+      Err(e) => {error!("Failed to encode legacy rejection: {}", e);}
+    }
+  }
+
+  fn handle(&mut self, client_id: String, raw_msg: Protocols) {
+    let (req_id, session) = match raw_msg {
+      Protocols::CPType(ref request) => (request.req_id(), request.session().map(str::to_string)),
+      Protocols::NPType(_) => (0, None),
+    };
+    let command = match raw_msg {
+      Protocols::CPType(ContactProtocol::ClientControl { command, .. }) => Command::Client(command),
+      Protocols::CPType(ContactProtocol::ServerControl { command, .. }) => Command::Server(command),
+      Protocols::CPType(ContactProtocol::User2UserMsg { target, content, time, .. }) => Command::Message { target, content, time },
+      Protocols::CPType(ContactProtocol::RoomMsg { room, content, time, .. }) => Command::RoomMessage { room, content, time },
+      Protocols::CPType(ContactProtocol::Response { .. }) | Protocols::NPType(_) => {
+        warn!("Wrong type from {}!", client_id);
+        self.reply_error(&client_id, req_id, MsgStatus::FAILED, ChatError::Protocol("Responses and notifications are only sent by the server".to_string()));
+        return;
+      },
+    };
+    if let Command::Client(ClientCommand::Register { handshake, .. }) = &command {
+      if handshake.version < MIN_PROTOCOL_VERSION {
+        self.reject_legacy(&client_id, handshake.version);
+        return;
+      }
+    }
+    let kind = command.kind();
+    let registering = kind == CommandKind::Client && command.name() == REGISTER_COMMAND;
+    if !registering {
+      match self.ctx.clients.get_mut(&client_id) {
+        Some(client) if session.as_deref() == Some(client.session.as_str()) => {
+          client.last_heard = Utc::now();
+          if client.presence.availability != Availability::Offline {
+            client.presence.last_seen = client.last_heard;
           }
         },
-        Protocols::CPType(ContactProtocol::User2UserMsg { state, target, content, time }) => {
-          let clients_lock = get_clients().lock().unwrap();
-          let target_client = clients_lock.get(&target);
-          let this_client = clients_lock.get(&client_id).unwrap();
-          if target_client.is_none(){
-            this_client.respond(&socket, MsgStatus::FAILED, "No such target".to_string(), None)
-              .unwrap_or_else(|e|{error!("Error {} occured during respond {}'s TextMsg", e.to_string(), client_id)});
-            continue;
+        registered => {
+          if registered.is_some() {
+            warn!("Request for {} without its session token", client_id);
+          } else {
+            warn!("Not registered client: {}", client_id);
           }
-          target_client.unwrap().notify(&socket, NotifyProtocol::MsgFromUser { sender: client_id, content: content })
-            .unwrap_or_else(|e|{error!("Error {} occured during notify {}", e.to_string(), target)});
+          let reject_msg = Protocols::CPType(ContactProtocol::Response { req_id, state: MsgStatus::REJECTED, reply: Reply::Reason("register".to_string()), time: Utc::now() });
+          self.ctx.socket.send_json(&client_id, &reject_msg, Some(0))
+            .unwrap_or_else(|e|{error!("Error {} occured during reject unregistered client {}", e, client_id);});
+          return;
         }
-        Protocols::NPType(_) => {
-          warn!("Wrong type from {}!", client_id);
-        } 
       }
     }
-  });
-  info!("Waiting for ROUTER thread");
-  let rep_state = main_receiver.recv().unwrap();
-  if rep_state == "init failed" {
-    error!("ROUTER thread err detected, exiting...");
-    return;
-  }else {
-    debug!("Receive ROUTER thread ok");
-  }
-  let control_socket = zmq_ctx.socket(zmq::DEALER).unwrap();
-  control_socket.set_identity("root".as_bytes()).unwrap();
-  control_socket.connect("tcp://localhost:23").unwrap();
-  let send_control = |socket: &zmq::Socket, control_msg: ContactProtocol|{
-    let protocol_msg;
-    match control_msg {
-      ContactProtocol::ServerControl { state, command, cmd_args, time } => {
-        protocol_msg = 
-          Protocols::CPType(ContactProtocol::ServerControl { state: state, command: command, cmd_args: cmd_args, time: time });
-      },
-      ContactProtocol::ClientControl { state, command, cmd_args, time } => {
-        protocol_msg = 
-          Protocols::CPType(ContactProtocol::ClientControl { state: state, command: command, cmd_args: cmd_args, time: time });
+    if kind == CommandKind::Server && self.ctx.role(&client_id) != Some(Role::Admin) {
+      warn!("Client {} try to use server cmd {}", client_id, command.name());
+      self.reply_error(&client_id, req_id, MsgStatus::REJECTED, ChatError::Unauthorized("Admin role required".to_string()));
+      return;
+    }
+    let Some(handler) = self.handlers.get_mut(&(kind, command.name().to_string())) else {
+      warn!("Client {} sent {}, which no handler serves", client_id, command.name());
+      let response_msg = Protocols::CPType(ContactProtocol::Response { req_id, state: MsgStatus::FAILED, reply: Reply::Reason(format!("Unknown command {}", command.name())), time: Utc::now() });
+      self.ctx.socket.send_frame(&client_id, &response_msg, self.ctx.codec_of(&client_id), Some(0))
+        .unwrap_or_else(|e|{error!("Error {} occured during respond {}", e, client_id)});
+      return;
+    };
+    debug!("{:?} command {} from {}", kind, command.name(), client_id);
+    // Answer a client that unregisters or is kicked by its own command in the codec it was speaking
+    let known_codec = (!registering).then(|| self.ctx.codec_of(&client_id));
+    let (state, reply) = match handler(&mut self.ctx, &client_id, command) {
+      Ok(reply) => (MsgStatus::ACCEPTED, reply),
+      Err(reason) if registering => {
+        warn!("Reject client {}: {}", client_id, reason);
+        (MsgStatus::REJECTED, Reply::Reason(reason))
       },
-      ContactProtocol::User2UserMsg { state, target, content, time } => {
-        protocol_msg = 
-          Protocols::CPType(ContactProtocol::User2UserMsg { state: state, target: target, content: content, time: time });
-      }
+      Err(reason) => (MsgStatus::FAILED, Reply::Reason(reason)),
     };
-    socket.send( &serde_json::to_vec(&protocol_msg).unwrap(),0).unwrap();
-  };
-  info!("Shell ok");
-  loop {
-    let user_input = input("Enter command: ");
-    let mut cmd_it = user_input.split_whitespace();
-    let cmd_type;
-    match cmd_it.next() {
-      Some(_val) => {cmd_type = _val;}
-      None => {
-        warn!("No cmd given, try again");
-        continue;
+    let codec = known_codec.unwrap_or_else(|| self.ctx.codec_of(&client_id));
+    let response_msg = Protocols::CPType(ContactProtocol::Response { req_id, state, reply, time: Utc::now() });
+    self.ctx.socket.send_frame(&client_id, &response_msg, codec, Some(0))
+      .unwrap_or_else(|e|{error!("Error {} occured during respond {}", e, client_id)});
+    self.ctx.flush_outbox();
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::config::QueueSection;
+  use crate::utils::ZmqJsonClient;
+  use std::path::Path;
+
+  /// A server bound to `endpoint` that keeps its stores in `dir`.
+  fn test_server(endpoint: &str, dir: &Path) -> ChatServer {
+    let config = ServerConfig { bind: endpoint.to_string(), history_path: dir.join("history.jsonl"), users_path: dir.join("users.json"),
+      queue: QueueSection { path: dir.join("queue.json"), ..QueueSection::default() }, ..ServerConfig::default() };
+    ChatServer::new(config).unwrap()
+  }
+
+  fn connect(zmq_ctx: &zmq::Context, endpoint: &str, client_id: &str) -> zmq::Socket {
+    let dealer = zmq_ctx.socket(zmq::DEALER).unwrap();
+    dealer.set_identity(client_id.as_bytes()).unwrap();
+    dealer.set_rcvtimeo(1000).unwrap();
+    dealer.connect(endpoint).unwrap();
+    dealer
+  }
+
+  /// Serve the next frame the router receives.
+  fn step(router: &mut Router) {
+    let (client_id, msg) = router.recv(0).expect("no frame reached the router");
+    router.handle(client_id, msg);
+  }
+
+  /// Send `request` with the session `register` grants, serve it and return the response, skipping the notifications queued before it.
+  fn request(router: &mut Router, dealer: &zmq::Socket, mut request: ContactProtocol) -> (MsgStatus, Reply) {
+    let client_id = dealer.get_identity().unwrap().unwrap();
+    request.set_session(&format!("{}-token", client_id));
+    request_as_is(router, dealer, request)
+  }
+
+  /// `request` without adding a session.
+  fn request_as_is(router: &mut Router, dealer: &zmq::Socket, mut request: ContactProtocol) -> (MsgStatus, Reply) {
+    request.set_req_id(1);
+    ZmqJsonClient::send_json(dealer, &Protocols::CPType(request), None).unwrap();
+    step(router);
+    loop {
+      if let Protocols::CPType(ContactProtocol::Response { state, reply, .. }) = ZmqJsonClient::recv_json(dealer, None).unwrap() {
+        return (state, reply);
       }
     }
-    match cmd_type {
-      "q" => {
-        let quit_msg = ContactProtocol::ServerControl { state: MsgStatus::SUBMITTED, command: "shutdown".to_string(), cmd_args: None, time: Utc::now() };
-        send_control(&control_socket, quit_msg);
-        break;
+  }
+
+  fn client_command(command: ClientCommand) -> ContactProtocol {
+    ContactProtocol::ClientControl { req_id: 0, session: None, state: MsgStatus::SUBMITTED, command, time: Utc::now() }
+  }
+
+  fn server_command(command: ServerCommand) -> ContactProtocol {
+    ContactProtocol::ServerControl { req_id: 0, session: None, state: MsgStatus::SUBMITTED, command, time: Utc::now() }
+  }
+
+  /// Register `client_id` on `dealer` with a session granted for it.
+  fn register(router: &mut Router, dealer: &zmq::Socket, client_id: &str) {
+    register_as(router, dealer, client_id, Role::User);
+  }
+
+  fn register_as(router: &mut Router, dealer: &zmq::Socket, client_id: &str, role: Role) {
+    let token = format!("{}-token", client_id);
+    router.ctx.users.grant_session(client_id, role, token.clone());
+    let register_msg = client_command(ClientCommand::Register { handshake: Handshake::current(), credentials: Credentials::Token(token) });
+    let (state, reply) = request(router, dealer, register_msg);
+    assert!(state == MsgStatus::ACCEPTED, "register {}: {}", state, reply);
+  }
+
+  #[test]
+  fn custom_register_does_not_skip_registration() {
+    let dir = tempfile::tempdir().unwrap();
+    let endpoint = "inproc://custom_register";
+    let mut server = test_server(endpoint, dir.path());
+    server.register_handler(CommandKind::Custom, REGISTER_COMMAND, |_, _, _| Ok(Reply::Custom(serde_json::json!("custom"))));
+    let zmq_ctx = server.zmq_ctx.clone();
+    let mut router = server.open().unwrap();
+    let dealer = connect(&zmq_ctx, endpoint, "mallory");
+
+    let custom = || client_command(ClientCommand::Custom { name: REGISTER_COMMAND.to_string(), args: serde_json::Value::Null });
+    match request(&mut router, &dealer, custom()) {
+      (MsgStatus::REJECTED, Reply::Reason(reason)) => assert_eq!(reason, "register"),
+      (state, reply) => panic!("expected the registration gate, got {} {}", state, reply),
+    }
+    assert!(!router.ctx.is_online("mallory"));
+
+    // Registered under its own kind, the custom handler neither replaces the built-in nor is replaced by it
+    register(&mut router, &dealer, "mallory");
+    match request(&mut router, &dealer, custom()) {
+      (MsgStatus::ACCEPTED, Reply::Custom(value)) => assert_eq!(value, serde_json::json!("custom")),
+      (state, reply) => panic!("expected the custom handler, got {} {}", state, reply),
+    }
+  }
+
+  /// `Protocols` as the protocol v1 client declares it.
+  #[derive(serde::Deserialize)]
+  enum V1Protocols {
+    CPType(V1ContactProtocol),
+  }
+
+  #[derive(serde::Deserialize)]
+  enum V1ContactProtocol {
+    ClientControl{state: MsgStatus, command: String, #[allow(dead_code)] cmd_args: Option<serde_json::Value>, #[allow(dead_code)] time: DateTime<Utc>},
+  }
+
+  #[test]
+  fn legacy_register_is_rejected_in_v1_shape() {
+    let dir = tempfile::tempdir().unwrap();
+    let endpoint = "inproc://legacy_register";
+    let server = test_server(endpoint, dir.path());
+    let zmq_ctx = server.zmq_ctx.clone();
+    let mut router = server.open().unwrap();
+    let dealer = connect(&zmq_ctx, endpoint, "oldtimer");
+
+    dealer.send(&br#"{"CPType":{"ClientControl":{"state":"SUBMITTED","command":"register","cmd_args":null,"time":"2024-01-01T00:00:00Z"}}}"#[..], 0).unwrap();
+    step(&mut router);
+    let raw = dealer.recv_bytes(0).unwrap();
+    match serde_json::from_slice::<V1Protocols>(&raw).unwrap() {
+      V1Protocols::CPType(V1ContactProtocol::ClientControl { state, command, .. }) => {
+        assert!(state == MsgStatus::REJECTED);
+        assert!(command.contains("no longer supported"), "unexpected reason {}", command);
       },
-      "client" => {
-        match cmd_it.next() {
-          Some(val) => {
-            match val {
-              "list" => {
-                let client_list_msg = 
-                  ContactProtocol::ClientControl { state: MsgStatus::SUBMITTED, command: "get_clients".to_string(), cmd_args: None, time: Utc::now() };
-                send_control(&control_socket, client_list_msg);
-                let response_json_vec = control_socket.recv_bytes(0).unwrap();  
-                let msg: Protocols = serde_json::from_slice(&response_json_vec).unwrap();
-                if let Protocols::CPType(ContactProtocol::ClientControl { state, command, cmd_args, time }) = msg{
-                  println!("clients: {}", cmd_args.unwrap());
-                }
-              },
-              _ => {
-                warn!("Invalid clint arg: {}", val);
-                continue;
-              }
-            }
+    }
+    assert!(!router.ctx.is_online("oldtimer"));
+  }
+
+  #[cfg(feature = "async")]
+  #[tokio::test]
+  async fn serve_async_clients() {
+    use crate::client::{AsyncChatClient, ClientEvent};
+    use crate::config::ClientConfig;
+    let dir = tempfile::tempdir().unwrap();
+    let endpoint = "inproc://serve_async_clients";
+    let mut users = UserDb::open(&dir.path().join("users.json")).unwrap();
+    users.add_user("alice", "pw1", Role::User).unwrap();
+    users.add_user("bob", "pw2", Role::User).unwrap();
+    drop(users);
+    let server = test_server(endpoint, dir.path());
+    let zmq_ctx = server.zmq_context().clone();
+    let serving = server.serve();
+    tokio::pin!(serving);
+
+    let chatting = async {
+      let config = ClientConfig { connect: endpoint.to_string(), ..ClientConfig::default() };
+      let mut alice = AsyncChatClient::connect_with(&zmq_ctx, &config, "alice", "pw1").await.unwrap();
+      let mut bob = AsyncChatClient::connect_with(&zmq_ctx, &config, "bob", "pw2").await.unwrap();
+      assert_eq!(alice.handshake().version, PROTOCOL_VERSION);
+      let mut events = bob.subscribe();
+      match alice.send_message("bob", MessageType::TextMsg { content: "hi bob".to_string() }).await {
+        Ok(Reply::Sent { queued, .. }) => assert!(!queued),
+        other => panic!("expected Sent, got {:?}", other.map(|reply| reply.to_string())),
+      }
+      loop {
+        match tokio::time::timeout(Duration::from_secs(5), events.recv()).await.expect("bob got no message") {
+          Some(ClientEvent::Notification(NotifyProtocol::MsgFromUser { sender, content, .. })) => {
+            assert_eq!(sender, "alice");
+            assert_eq!(content.to_string(), "hi bob");
+            break;
           },
-          None => {
-            warn!("client needs args");
-            continue;
-          }
+          Some(_) => {},
+          None => panic!("bob's client stopped"),
         }
       }
-      _ => {
-        warn!("Unknow cmd");
-        continue;
+      alice.close().await;
+      bob.close().await;
+    };
+    tokio::select! {
+      served = &mut serving => panic!("server stopped: {:?}", served.err().map(|e| e.to_string())),
+      _ = chatting => {},
+    }
+  }
+
+  #[test]
+  fn blocking_client_round_trip() {
+    use crate::client::ChatClient;
+    use crate::config::ClientConfig;
+    let dir = tempfile::tempdir().unwrap();
+    let endpoint = "inproc://blocking_client";
+    let mut users = UserDb::open(&dir.path().join("users.json")).unwrap();
+    users.add_user("alice", "pw", Role::Admin).unwrap();
+    drop(users);
+    let server = test_server(endpoint, dir.path());
+    let zmq_ctx = server.zmq_context().clone();
+    let serving = std::thread::spawn(move ||{server.run().map_err(|e| e.to_string())});
+
+    let config = ClientConfig { connect: endpoint.to_string(), ..ClientConfig::default() };
+    let alice = ChatClient::connect_with(&zmq_ctx, &config, "alice", "pw").unwrap();
+    alice.set_presence(Availability::Away, Some("reading".to_string())).unwrap();
+    let presences = alice.list_clients().unwrap();
+    assert_eq!(presences.len(), 1);
+    assert_eq!(presences[0].client_id, "alice");
+    assert_eq!(presences[0].presence.status.as_deref(), Some("reading"));
+    assert!(alice.fetch_key("alice").is_err());
+    let shutdown = ContactProtocol::ServerControl { req_id: 0, session: None, state: MsgStatus::SUBMITTED,
+      command: ServerCommand::Shutdown { reason: None, grace_secs: 0 }, time: Utc::now() };
+    assert!(matches!(alice.request(shutdown), Ok(Reply::Done)));
+    serving.join().unwrap().unwrap();
+    alice.close();
+  }
+
+  #[test]
+  fn server_commands_need_admin() {
+    let dir = tempfile::tempdir().unwrap();
+    let endpoint = "inproc://server_commands_need_admin";
+    let server = test_server(endpoint, dir.path());
+    let zmq_ctx = server.zmq_ctx.clone();
+    let mut router = server.open().unwrap();
+    let dealer = connect(&zmq_ctx, endpoint, "alice");
+    let bob = connect(&zmq_ctx, endpoint, "bob");
+    register(&mut router, &dealer, "alice");
+    register(&mut router, &bob, "bob");
+    router.ctx.users.add_user("bob", "pw", Role::User).unwrap();
+
+    let commands = [
+      ServerCommand::Shutdown { reason: None, grace_secs: 0 },
+      ServerCommand::Kick { client_id: "bob".to_string(), reason: None },
+      ServerCommand::Ban { client_id: "bob".to_string(), until: None, reason: None },
+      ServerCommand::Broadcast { content: "hello".to_string() },
+      ServerCommand::Info { client_id: "bob".to_string() },
+    ];
+    for command in commands {
+      let name = command.name().to_string();
+      match request(&mut router, &dealer, server_command(command)) {
+        (MsgStatus::REJECTED, Reply::Error { kind, .. }) => assert!(kind == crate::utils::ErrorKind::Unauthorized, "{}", name),
+        (state, reply) => panic!("expected {} to be unauthorized, got {} {}", name, state, reply),
       }
     }
+    assert!(router.ctx.shutdown_at.is_none());
+    assert!(router.ctx.is_online("bob"));
+    assert!(router.ctx.users.active_ban("bob").is_none());
+    assert!(next_notification(&bob).is_none());
+  }
+
+  #[test]
+  fn admins_kick_ban_broadcast_and_inspect() {
+    let dir = tempfile::tempdir().unwrap();
+    let endpoint = "inproc://admin_commands";
+    let server = test_server(endpoint, dir.path());
+    let zmq_ctx = server.zmq_ctx.clone();
+    let mut router = server.open().unwrap();
+    router.ctx.users.add_user("alice", "pw", Role::User).unwrap();
+    let root = connect(&zmq_ctx, endpoint, "root");
+    let alice = connect(&zmq_ctx, endpoint, "alice");
+    let bob = connect(&zmq_ctx, endpoint, "bob");
+    register_as(&mut router, &root, "root", Role::Admin);
+    register(&mut router, &alice, "alice");
+    register(&mut router, &bob, "bob");
+
+    match request(&mut router, &root, server_command(ServerCommand::Info { client_id: "alice".to_string() })) {
+      (MsgStatus::ACCEPTED, Reply::ClientInfo(info)) => {
+        assert_eq!(info.client_id, "alice");
+        assert_eq!(info.admin, Some(false));
+        assert_eq!(info.presence.unwrap().availability, Availability::Online);
+        assert!(info.login_time.is_some());
+        assert_eq!(info.handshake.unwrap().version, PROTOCOL_VERSION);
+        assert_eq!(info.ban, None);
+      },
+      (state, reply) => panic!("expected alice's info, got {} {}", state, reply),
+    }
+
+    let broadcast = server_command(ServerCommand::Broadcast { content: "maintenance at noon".to_string() });
+    assert!(request(&mut router, &root, broadcast).0 == MsgStatus::ACCEPTED);
+    for dealer in [&alice, &bob] {
+      match next_notification(dealer) {
+        Some(NotifyProtocol::Broadcast { sender, content, .. }) => {
+          assert_eq!(sender, "root");
+          assert_eq!(content, "maintenance at noon");
+        },
+        _ => panic!("expected the broadcast"),
+      }
+    }
+    assert!(next_notification(&root).is_none());
+
+    let kick = server_command(ServerCommand::Kick { client_id: "bob".to_string(), reason: Some("flooding".to_string()) });
+    assert!(request(&mut router, &root, kick).0 == MsgStatus::ACCEPTED);
+    match next_notification(&bob) {
+      Some(NotifyProtocol::Kicked { reason }) => assert_eq!(reason, "flooding"),
+      _ => panic!("expected bob to be kicked"),
+    }
+    assert!(!router.ctx.clients.contains_key("bob"));
+
+    let ban = server_command(ServerCommand::Ban { client_id: "alice".to_string(), until: None, reason: Some("spamming".to_string()) });
+    assert!(request(&mut router, &root, ban).0 == MsgStatus::ACCEPTED);
+    match next_notification(&alice) {
+      Some(NotifyProtocol::Kicked { reason }) => assert!(reason.contains("spamming"), "{}", reason),
+      _ => panic!("expected alice to be kicked"),
+    }
+    assert!(!router.ctx.clients.contains_key("alice"));
+    let register_msg = client_command(ClientCommand::Register { handshake: Handshake::current(), credentials: Credentials::Password("pw".to_string()) });
+    match request(&mut router, &alice, register_msg) {
+      (state, Reply::Reason(reason)) if state != MsgStatus::ACCEPTED => assert!(reason.contains("spamming"), "{}", reason),
+      (state, reply) => panic!("expected the ban to refuse the register, got {} {}", state, reply),
+    }
+    assert!(!router.ctx.is_online("alice"));
+  }
+
+
+  #[test]
+  fn silent_clients_are_evicted() {
+    let dir = tempfile::tempdir().unwrap();
+    let endpoint = "inproc://eviction";
+    let server = test_server(endpoint, dir.path());
+    let zmq_ctx = server.zmq_ctx.clone();
+    let mut router = server.open().unwrap();
+    router.client_timeout = chrono::Duration::milliseconds(300);
+    let silent = connect(&zmq_ctx, endpoint, "silent");
+    let alive = connect(&zmq_ctx, endpoint, "alive");
+    let quiet = connect(&zmq_ctx, endpoint, "quiet");
+    register(&mut router, &silent, "silent");
+    register(&mut router, &alive, "alive");
+    // Registered without the Heartbeat capability
+    router.ctx.users.grant_session("quiet", Role::User, "quiet-token".to_string());
+    let mut no_heartbeat = Handshake::current();
+    no_heartbeat.capabilities.retain(|capability| *capability != Capability::Heartbeat);
+    let register_msg = client_command(ClientCommand::Register { handshake: no_heartbeat, credentials: Credentials::Token("quiet-token".to_string()) });
+    assert!(request(&mut router, &quiet, register_msg).0 == MsgStatus::ACCEPTED);
+
+    for round in 0..5 {
+      std::thread::sleep(Duration::from_millis(150));
+      assert!(request(&mut router, &alive, client_command(ClientCommand::Heartbeat)).0 == MsgStatus::ACCEPTED);
+      router.last_sweep -= EVICTION_SWEEP;
+      router.sweep();
+      if round == 0 {
+        assert!(router.ctx.is_online("silent"), "evicted before its timeout");
+      }
+    }
+    assert!(!router.ctx.is_online("silent"));
+    assert!(router.ctx.is_online("alive"));
+    // Without heartbeats a client is only evicted once idle for IDLE_TIMEOUTS timeouts
+    assert!(router.ctx.is_online("quiet"));
+    router.ctx.clients.get_mut("quiet").unwrap().last_heard -= router.client_timeout * IDLE_TIMEOUTS;
+    router.last_sweep -= EVICTION_SWEEP;
+    router.sweep();
+    assert!(!router.ctx.is_online("quiet"));
+    // An evicted client has to register again
+    match request(&mut router, &silent, client_command(ClientCommand::Heartbeat)) {
+      (MsgStatus::REJECTED, Reply::Reason(reason)) => assert_eq!(reason, "register"),
+      (state, reply) => panic!("expected the registration gate, got {} {}", state, reply),
+    }
+  }
+
+  /// The next notification `dealer` receives, None once nothing arrives within its receive timeout.
+  fn next_notification(dealer: &zmq::Socket) -> Option<NotifyProtocol> {
+    match ZmqJsonClient::recv_json(dealer, None) {
+      Ok(Protocols::NPType(notification)) => Some(notification),
+      Ok(_) => panic!("expected a notification, got a response"),
+      Err(_) => None,
+    }
+  }
+
+  fn watched_availability(dealer: &zmq::Socket, watched: &str) -> Availability {
+    match next_notification(dealer) {
+      Some(NotifyProtocol::StatusChanged { client_id, presence }) if client_id == watched => presence.availability,
+      _ => panic!("expected a status change of {}", watched),
+    }
+  }
+
+  #[test]
+  fn watchers_follow_presence_until_unwatch() {
+    let dir = tempfile::tempdir().unwrap();
+    let endpoint = "inproc://watchers";
+    let server = test_server(endpoint, dir.path());
+    let zmq_ctx = server.zmq_ctx.clone();
+    let mut router = server.open().unwrap();
+    router.ctx.users.add_user("bob", "pw", Role::User).unwrap();
+    let alice = connect(&zmq_ctx, endpoint, "alice");
+    let bob = connect(&zmq_ctx, endpoint, "bob");
+    register(&mut router, &alice, "alice");
+    let watch = |client_id: &str| client_command(ClientCommand::Watch { client_id: client_id.to_string() });
+    assert!(request(&mut router, &alice, watch("bob")).0 == MsgStatus::ACCEPTED);
+    assert!(request(&mut router, &alice, watch("nobody")).0 == MsgStatus::FAILED);
+
+    register(&mut router, &bob, "bob");
+    assert_eq!(watched_availability(&alice, "bob"), Availability::Online);
+    let away = client_command(ClientCommand::SetPresence { availability: Availability::Away, status: Some("lunch".to_string()) });
+    assert!(request(&mut router, &bob, away).0 == MsgStatus::ACCEPTED);
+    match next_notification(&alice) {
+      Some(NotifyProtocol::StatusChanged { presence, .. }) => {
+        assert_eq!(presence.availability, Availability::Away);
+        assert_eq!(presence.status.as_deref(), Some("lunch"));
+      },
+      _ => panic!("expected bob to be away"),
+    }
+    assert!(request(&mut router, &bob, client_command(ClientCommand::Unregister)).0 == MsgStatus::ACCEPTED);
+    assert_eq!(watched_availability(&alice, "bob"), Availability::Offline);
+
+    let unwatch = client_command(ClientCommand::Unwatch { client_id: "bob".to_string() });
+    assert!(request(&mut router, &alice, unwatch).0 == MsgStatus::ACCEPTED);
+    register(&mut router, &bob, "bob");
+    assert!(request(&mut router, &bob, client_command(ClientCommand::Unregister)).0 == MsgStatus::ACCEPTED);
+    assert!(next_notification(&alice).is_none());
+  }
+
+  #[test]
+  fn invisible_clients_keep_their_last_seen() {
+    let dir = tempfile::tempdir().unwrap();
+    let endpoint = "inproc://invisible";
+    let server = test_server(endpoint, dir.path());
+    let zmq_ctx = server.zmq_ctx.clone();
+    let mut router = server.open().unwrap();
+    let alice = connect(&zmq_ctx, endpoint, "alice");
+    let bob = connect(&zmq_ctx, endpoint, "bob");
+    register(&mut router, &alice, "alice");
+    register(&mut router, &bob, "bob");
+    let hidden = client_command(ClientCommand::SetPresence { availability: Availability::Offline, status: Some("hiding".to_string()) });
+    assert!(request(&mut router, &bob, hidden).0 == MsgStatus::ACCEPTED);
+    let went_invisible = router.ctx.clients["bob"].presence.last_seen;
+    std::thread::sleep(Duration::from_millis(20));
+    assert!(request(&mut router, &bob, client_command(ClientCommand::Heartbeat)).0 == MsgStatus::ACCEPTED);
+    assert!(router.ctx.clients["bob"].last_heard > went_invisible);
+    match request(&mut router, &alice, client_command(ClientCommand::ListClients)) {
+      (MsgStatus::ACCEPTED, Reply::Presences(presences)) => {
+        let bob_presence = &presences.iter().find(|listed| listed.client_id == "bob").unwrap().presence;
+        assert_eq!(bob_presence.availability, Availability::Offline);
+        assert_eq!(bob_presence.last_seen, went_invisible);
+        assert_eq!(bob_presence.status, None);
+      },
+      (state, reply) => panic!("expected presences, got {} {}", state, reply),
+    }
+  }
+
+  fn direct_message(target: &str, text: &str) -> ContactProtocol {
+    ContactProtocol::User2UserMsg { req_id: 0, session: None, state: MsgStatus::SUBMITTED, target: target.to_string(),
+      content: MessageType::TextMsg { content: text.to_string() }, time: Utc::now() }
+  }
+
+  /// Send a direct message that is delivered right away and return its id.
+  fn send_direct(router: &mut Router, dealer: &zmq::Socket, target: &str, text: &str) -> u64 {
+    match request(router, dealer, direct_message(target, text)) {
+      (MsgStatus::ACCEPTED, Reply::Sent { msg_id, queued: false }) => msg_id,
+      (state, reply) => panic!("expected the message to be sent, got {} {}", state, reply),
+    }
+  }
+
+  fn expect_receipt(dealer: &zmq::Socket, expected_id: u64, expected_from: &str, expected_state: MsgStatus) {
+    match next_notification(dealer) {
+      Some(NotifyProtocol::Receipt { msg_id, from, state, .. }) => {
+        assert_eq!((msg_id, from.as_str()), (expected_id, expected_from));
+        assert!(state == expected_state, "expected {}, got {}", expected_state, state);
+      },
+      _ => panic!("expected a receipt"),
+    }
   }
-  router_handle.join().unwrap();
-  info!("Total exiting...");
-}
 
+  #[test]
+  fn receipts_reach_the_sender() {
+    let dir = tempfile::tempdir().unwrap();
+    let endpoint = "inproc://receipts";
+    let server = test_server(endpoint, dir.path());
+    let zmq_ctx = server.zmq_ctx.clone();
+    let mut router = server.open().unwrap();
+    let alice = connect(&zmq_ctx, endpoint, "alice");
+    let bob = connect(&zmq_ctx, endpoint, "bob");
+    register(&mut router, &alice, "alice");
+    register(&mut router, &bob, "bob");
+
+    let msg_id = send_direct(&mut router, &alice, "bob", "ping");
+    expect_receipt(&alice, msg_id, "bob", MsgStatus::DELIVERED);
+    match next_notification(&bob) {
+      Some(NotifyProtocol::MsgFromUser { msg_id: received, sender, .. }) => assert_eq!((received, sender.as_str()), (msg_id, "alice")),
+      _ => panic!("expected bob to get the message"),
+    }
+
+    // Only the recipient can mark it read, and only once
+    let mark_read = || client_command(ClientCommand::MarkRead { msg_id });
+    assert!(request(&mut router, &alice, mark_read()).0 == MsgStatus::FAILED);
+    assert!(request(&mut router, &bob, mark_read()).0 == MsgStatus::ACCEPTED);
+    expect_receipt(&alice, msg_id, "bob", MsgStatus::READ);
+    assert!(request(&mut router, &bob, mark_read()).0 == MsgStatus::FAILED);
+    assert!(next_notification(&alice).is_none());
+  }
+
+  #[test]
+  fn message_ids_count_up_across_restarts() {
+    let dir = tempfile::tempdir().unwrap();
+    let endpoint = "inproc://message_ids";
+    let mut last_id = 0;
+    for _ in 0..2 {
+      let server = test_server(endpoint, dir.path());
+      let zmq_ctx = server.zmq_ctx.clone();
+      let mut router = server.open().unwrap();
+      let alice = connect(&zmq_ctx, endpoint, "alice");
+      let bob = connect(&zmq_ctx, endpoint, "bob");
+      register(&mut router, &alice, "alice");
+      register(&mut router, &bob, "bob");
+      for _ in 0..2 {
+        let msg_id = send_direct(&mut router, &alice, "bob", "hi");
+        assert_eq!(msg_id, last_id + 1);
+        last_id = msg_id;
+      }
+    }
+  }
+
+  #[test]
+  fn shutdown_warns_refuses_registers_and_saves_state() {
+    let dir = tempfile::tempdir().unwrap();
+    let endpoint = "inproc://shutdown";
+    let server = test_server(endpoint, dir.path());
+    let zmq_ctx = server.zmq_ctx.clone();
+    let mut router = server.open().unwrap();
+    router.ctx.users.add_user("carol", "pw", Role::User).unwrap();
+    let root = connect(&zmq_ctx, endpoint, "root");
+    let alice = connect(&zmq_ctx, endpoint, "alice");
+    register_as(&mut router, &root, "root", Role::Admin);
+    register(&mut router, &alice, "alice");
+    let sent_id = send_direct(&mut router, &alice, "root", "before the shutdown");
+    assert!(next_notification(&root).is_some());
+    match request(&mut router, &alice, direct_message("carol", "see you later")) {
+      (MsgStatus::ACCEPTED, Reply::Sent { queued: true, .. }) => {},
+      (state, reply) => panic!("expected the message to be queued, got {} {}", state, reply),
+    }
+
+    let shutdown = server_command(ServerCommand::Shutdown { reason: Some("upgrade".to_string()), grace_secs: 1 });
+    assert!(request(&mut router, &root, shutdown).0 == MsgStatus::ACCEPTED);
+    let started = Instant::now();
+    match next_notification(&alice) {
+      Some(NotifyProtocol::ServerShutdown { reason, grace_secs, .. }) => {
+        assert_eq!(reason.as_deref(), Some("upgrade"));
+        assert_eq!(grace_secs, 1);
+      },
+      _ => panic!("expected the shutdown notice"),
+    }
+    let late = connect(&zmq_ctx, endpoint, "late");
+    router.ctx.users.grant_session("late", Role::User, "late-token".to_string());
+    let register_msg = client_command(ClientCommand::Register { handshake: Handshake::current(), credentials: Credentials::Token("late-token".to_string()) });
+    match request(&mut router, &late, register_msg) {
+      (state, Reply::Reason(reason)) if state != MsgStatus::ACCEPTED => assert_eq!(reason, "Server is shutting down"),
+      (state, reply) => panic!("expected the register to be refused, got {} {}", state, reply),
+    }
+    router.run();
+    assert!(started.elapsed() >= Duration::from_millis(900), "stopped before the grace period");
+
+    let server = test_server(endpoint, dir.path());
+    let mut router = server.open().unwrap();
+    let (history, total) = router.ctx.store.query("root", Some("alice"), 0, 10);
+    assert_eq!(total, 1);
+    assert_eq!(history[0].msg_id, sent_id);
+    let queued = router.ctx.offline_queue.take("carol", |_| true);
+    assert_eq!(queued.len(), 1);
+    assert_eq!(queued[0].sender, "alice");
+  }
+
+  #[test]
+  fn room_messages_reach_the_other_members() {
+    let dir = tempfile::tempdir().unwrap();
+    let endpoint = "inproc://rooms";
+    let server = test_server(endpoint, dir.path());
+    let zmq_ctx = server.zmq_ctx.clone();
+    let mut router = server.open().unwrap();
+    let members: Vec<(&str, zmq::Socket)> = ["alice", "bob", "carol"].into_iter().map(|id| (id, connect(&zmq_ctx, endpoint, id))).collect();
+    let outsider = connect(&zmq_ctx, endpoint, "dave");
+    for (id, dealer) in &members {
+      register(&mut router, dealer, id);
+    }
+    register(&mut router, &outsider, "dave");
+    let (_, alice) = &members[0];
+    assert!(request(&mut router, alice, client_command(ClientCommand::CreateRoom { room: "lobby".to_string() })).0 == MsgStatus::ACCEPTED);
+    for (_, dealer) in &members[1..] {
+      assert!(request(&mut router, dealer, client_command(ClientCommand::JoinRoom { room: "lobby".to_string() })).0 == MsgStatus::ACCEPTED);
+    }
+    let room_message = |text: &str| ContactProtocol::RoomMsg { req_id: 0, session: None, state: MsgStatus::SUBMITTED, room: "lobby".to_string(),
+      content: MessageType::TextMsg { content: text.to_string() }, time: Utc::now() };
+
+    assert!(request(&mut router, alice, room_message("hello all")).0 == MsgStatus::ACCEPTED);
+    for (_, dealer) in &members[1..] {
+      match next_notification(dealer) {
+        Some(NotifyProtocol::MsgFromRoom { room, sender, content: MessageType::TextMsg { content }, .. }) => {
+          assert_eq!((room.as_str(), sender.as_str(), content.as_str()), ("lobby", "alice", "hello all"));
+        },
+        _ => panic!("expected the room message"),
+      }
+    }
+    assert!(next_notification(alice).is_none());
+
+    match request(&mut router, &outsider, room_message("let me in")) {
+      (state, Reply::Reason(reason)) if state != MsgStatus::ACCEPTED => assert_eq!(reason, "Not in room lobby"),
+      (state, reply) => panic!("expected the outsider to be refused, got {} {}", state, reply),
+    }
+    for (_, dealer) in &members {
+      assert!(next_notification(dealer).is_none());
+    }
+
+    assert!(request(&mut router, alice, client_command(ClientCommand::DeleteRoom { room: "lobby".to_string() })).0 == MsgStatus::ACCEPTED);
+    for (_, dealer) in &members[1..] {
+      match next_notification(dealer) {
+        Some(NotifyProtocol::RoomDeleted { room, by }) => assert_eq!((room.as_str(), by.as_str()), ("lobby", "alice")),
+        _ => panic!("expected the room to be deleted"),
+      }
+    }
+    assert!(next_notification(&outsider).is_none());
+  }
+
+  #[test]
+  fn offline_messages_survive_a_restart() {
+    let dir = tempfile::tempdir().unwrap();
+    let endpoint = "inproc://offline_queue";
+    let server = test_server(endpoint, dir.path());
+    let zmq_ctx = server.zmq_ctx.clone();
+    let mut router = server.open().unwrap();
+    router.ctx.users.add_user("bob", "pw", Role::User).unwrap();
+    let alice = connect(&zmq_ctx, endpoint, "alice");
+    register(&mut router, &alice, "alice");
+    let sealed = ContactProtocol::User2UserMsg { req_id: 0, session: None, state: MsgStatus::SUBMITTED, target: "bob".to_string(),
+      content: MessageType::EncryptedMsg { sender_key: "k".repeat(40), nonce: vec![7; 24], ciphertext: vec![1, 2, 3] }, time: Utc::now() };
+    let mut queued_ids = Vec::new();
+    for msg in [direct_message("bob", "while you were out"), sealed] {
+      match request(&mut router, &alice, msg) {
+        (MsgStatus::ACCEPTED, Reply::Sent { msg_id, queued: true }) => queued_ids.push(msg_id),
+        (state, reply) => panic!("expected the message to be queued, got {} {}", state, reply),
+      }
+    }
+    drop(router);
+
+    let server = test_server(endpoint, dir.path());
+    let zmq_ctx = server.zmq_ctx.clone();
+    let mut router = server.open().unwrap();
+    let bob = connect(&zmq_ctx, endpoint, "bob");
+    let mut plain = Handshake::current();
+    plain.capabilities.retain(|capability| *capability != Capability::Encryption);
+    router.ctx.users.grant_session("bob", Role::User, "bob-token".to_string());
+    let register_plain = client_command(ClientCommand::Register { handshake: plain, credentials: Credentials::Token("bob-token".to_string()) });
+    assert!(request(&mut router, &bob, register_plain).0 == MsgStatus::ACCEPTED);
+    match next_notification(&bob) {
+      Some(NotifyProtocol::MsgFromUser { msg_id, sender, content: MessageType::TextMsg { content }, .. }) => {
+        assert_eq!((msg_id, sender.as_str(), content.as_str()), (queued_ids[0], "alice", "while you were out"));
+      },
+      _ => panic!("expected the queued text message"),
+    }
+    assert!(next_notification(&bob).is_none());
+
+    // The encrypted one waits for a client that can decrypt it
+    assert!(request(&mut router, &bob, client_command(ClientCommand::Unregister)).0 == MsgStatus::ACCEPTED);
+    register(&mut router, &bob, "bob");
+    match next_notification(&bob) {
+      Some(NotifyProtocol::MsgFromUser { msg_id, content: MessageType::EncryptedMsg { .. }, .. }) => assert_eq!(msg_id, queued_ids[1]),
+      _ => panic!("expected the queued encrypted message"),
+    }
+    assert!(router.ctx.offline_queue.take("bob", |_| true).is_empty());
+  }
+
+  #[test]
+  fn malformed_frames_get_a_framing_reply() {
+    let dir = tempfile::tempdir().unwrap();
+    let endpoint = "inproc://malformed_frames";
+    let server = test_server(endpoint, dir.path());
+    let zmq_ctx = server.zmq_ctx.clone();
+    let mut router = server.open().unwrap();
+    let dealer = connect(&zmq_ctx, endpoint, "alice");
+    register(&mut router, &dealer, "alice");
+
+    let oversized = vec![b' '; MAX_FRAME_SIZE + 1];
+    let malformed: [&[&[u8]]; 3] = [&[b"{}", b"{}"], &[&oversized], &[b""]];
+    for parts in malformed {
+      dealer.send_multipart(parts, 0).unwrap();
+      assert!(router.recv(0).is_none());
+      match ZmqJsonClient::recv_json(&dealer, None).unwrap() {
+        Protocols::CPType(ContactProtocol::Response { state: MsgStatus::FAILED, reply: Reply::Error { kind, .. }, .. }) =>
+          assert_eq!(kind, crate::utils::ErrorKind::Framing),
+        _ => panic!("expected a framing error"),
+      }
+    }
+    // Still registered and served
+    assert!(request(&mut router, &dealer, client_command(ClientCommand::Heartbeat)).0 == MsgStatus::ACCEPTED);
+  }
+
+  #[test]
+  fn routing_id_alone_does_not_take_over_a_session() {
+    let dir = tempfile::tempdir().unwrap();
+    let endpoint = "inproc://session_binding";
+    let server = test_server(endpoint, dir.path());
+    let zmq_ctx = server.zmq_ctx.clone();
+    let mut router = server.open().unwrap();
+    let root = connect(&zmq_ctx, endpoint, "root");
+    register_as(&mut router, &root, "root", Role::Admin);
+    // Frames under the routing id of the registered shell that lack its token, as an impostor's would
+    let add_admin = || ContactProtocol::ServerControl { req_id: 0, session: None, state: MsgStatus::SUBMITTED,
+      command: ServerCommand::AddUser { client_id: "mallory".to_string(), password: Credentials::Password("pw".to_string()), admin: true }, time: Utc::now() };
+    let mut guessed = add_admin();
+    guessed.set_session("guessed");
+    for attempt in [add_admin(), guessed] {
+      match request_as_is(&mut router, &root, attempt) {
+        (MsgStatus::REJECTED, Reply::Reason(reason)) => assert_eq!(reason, "register"),
+        (state, reply) => panic!("expected the registration gate, got {} {}", state, reply),
+      }
+    }
+    assert!(!router.ctx.users.contains("mallory"));
+    assert!(router.ctx.is_online("root"));
+    assert!(request(&mut router, &root, add_admin()).0 == MsgStatus::ACCEPTED);
+    assert!(router.ctx.users.contains("mallory"));
+  }
+}
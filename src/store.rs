@@ -0,0 +1,141 @@
+use log::{info, warn};
+use std::{fs::{File, OpenOptions}, io::{BufRead, BufReader, Write}, path::{Path, PathBuf}};
+use crate::utils::HistoryEntry;
+
+/// Where routed messages are recorded, so history survives server restarts.
+pub trait MessageStore {
+  fn append(&mut self, entry: HistoryEntry) -> Result<(), Box<dyn std::error::Error>>;
+  /// Messages `user` sent or received, optionally only those exchanged with `peer`, newest first.
+  /// Returns the requested page and the total number of matching messages.
+  fn query(&self, user: &str, peer: Option<&str>, offset: usize, limit: usize) -> (Vec<HistoryEntry>, usize);
+  fn flush(&mut self) -> Result<(), Box<dyn std::error::Error>>;
+}
+
+/// Append-only log with one JSON encoded `HistoryEntry` per line, replayed into memory on open.
+pub struct FileStore {
+  path: PathBuf,
+  file: File,
+  entries: Vec<HistoryEntry>,
+}
+
+impl FileStore {
+  pub fn open(path: &Path) -> Result<FileStore, Box<dyn std::error::Error>> {
+    let mut entries = Vec::new();
+    if path.exists() {
+      let reader = BufReader::new(File::open(path)?);
+      for (line_no, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+          continue;
+        }
+        match serde_json::from_str(&line) {
+          Ok(entry) => {entries.push(entry);},
+          Err(e) => {warn!("Skip corrupt history line {} in {}: {}", line_no + 1, path.display(), e);}
+        }
+      }
+    }
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    info!("History store {} opened with {} messages", path.display(), entries.len());
+    Ok(FileStore { path: path.to_path_buf(), file, entries })
+  }
+
+  /// Highest message id recorded so far, 0 when there is none.
+  pub fn last_msg_id(&self) -> u64 {
+    self.entries.iter().map(|entry| entry.msg_id).max().unwrap_or(0)
+  }
+}
+
+impl MessageStore for FileStore {
+  fn append(&mut self, entry: HistoryEntry) -> Result<(), Box<dyn std::error::Error>> {
+    let mut line = serde_json::to_vec(&entry)?;
+    line.push(b'\n');
+    self.file.write_all(&line)?;
+    self.entries.push(entry);
+    Ok(())
+  }
+
+  fn query(&self, user: &str, peer: Option<&str>, offset: usize, limit: usize) -> (Vec<HistoryEntry>, usize) {
+    let matching: Vec<&HistoryEntry> = self.entries.iter().rev()
+      .filter(|entry| entry.sender == user || entry.target == user)
+      .filter(|entry| peer.is_none_or(|peer| entry.sender == peer || entry.target == peer))
+      .collect();
+    let total = matching.len();
+    (matching.into_iter().skip(offset).take(limit).cloned().collect(), total)
+  }
+
+  fn flush(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+    self.file.sync_data().map_err(|e| format!("Failed to sync {}: {}", self.path.display(), e).into())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use chrono::Utc;
+  use crate::utils::MessageType;
+
+  fn entry(msg_id: u64, sender: &str, target: &str) -> HistoryEntry {
+    HistoryEntry { msg_id, sender: sender.to_string(), target: target.to_string(),
+      content: MessageType::TextMsg { content: format!("message {}", msg_id) }, time: Utc::now() }
+  }
+
+  fn ids(page: &[HistoryEntry]) -> Vec<u64> {
+    page.iter().map(|entry| entry.msg_id).collect()
+  }
+
+  #[test]
+  fn appends_one_json_line_per_message() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("history.jsonl");
+    let mut store = FileStore::open(&path).unwrap();
+    store.append(entry(1, "alice", "bob")).unwrap();
+    store.append(entry(2, "bob", "alice")).unwrap();
+    store.flush().unwrap();
+    let text = std::fs::read_to_string(&path).unwrap();
+    let lines: Vec<&str> = text.lines().collect();
+    assert_eq!(lines.len(), 2);
+    let second: HistoryEntry = serde_json::from_str(lines[1]).unwrap();
+    assert_eq!((second.msg_id, second.sender.as_str()), (2, "bob"));
+  }
+
+  #[test]
+  fn replays_on_reopen() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("history.jsonl");
+    let mut store = FileStore::open(&path).unwrap();
+    store.append(entry(1, "alice", "bob")).unwrap();
+    store.append(entry(2, "carol", "alice")).unwrap();
+    drop(store);
+    let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+    file.write_all(b"not json\n\n").unwrap();
+
+    let mut reopened = FileStore::open(&path).unwrap();
+    assert_eq!(ids(&reopened.query("alice", None, 0, 10).0), [2, 1]);
+    reopened.append(entry(3, "bob", "alice")).unwrap();
+    drop(reopened);
+    let reopened = FileStore::open(&path).unwrap();
+    let (page, total) = reopened.query("alice", Some("bob"), 0, 10);
+    assert_eq!((ids(&page), total), (vec![3, 1], 2));
+    assert_eq!(reopened.last_msg_id(), 3);
+  }
+
+  #[test]
+  fn pages_newest_first() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut store = FileStore::open(&dir.path().join("history.jsonl")).unwrap();
+    for msg_id in 1..=5 {
+      store.append(entry(msg_id, "alice", "bob")).unwrap();
+    }
+    store.append(entry(6, "carol", "dave")).unwrap();
+
+    let (page, total) = store.query("bob", None, 0, 2);
+    assert_eq!((ids(&page), total), (vec![5, 4], 5));
+    assert_eq!(ids(&store.query("bob", None, 2, 2).0), [3, 2]);
+    assert_eq!(ids(&store.query("bob", None, 4, 2).0), [1]);
+    let (page, total) = store.query("bob", None, 0, 0);
+    assert_eq!((page.len(), total), (0, 5));
+    let (page, total) = store.query("bob", None, 10, 2);
+    assert_eq!((page.len(), total), (0, 5));
+    assert_eq!(store.query("bob", Some("carol"), 0, 10).1, 0);
+  }
+}
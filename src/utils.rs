@@ -1,32 +1,333 @@
-use std::{fmt::write, io::Write};
-use log::error;
+use std::{io::{IsTerminal, Write}, sync::Mutex};
+use log::{debug, error};
 use serde::{Serialize, Deserialize};
 use chrono::{DateTime, Utc};
 
+/// What went wrong moving a frame or serving it, kept apart so callers can react to the kind instead of downcasting.
+#[derive(Debug)]
+pub enum ChatError {
+  /// The socket failed, or had nothing to receive before its timeout.
+  Transport(std::io::Error),
+  /// The message parts do not have the expected shape.
+  Framing(String),
+  /// The payload is not a `Protocols` envelope in the codec it claims.
+  Decode(Box<dyn std::error::Error + Send + Sync>),
+  /// The envelope could not be serialized in the agreed codec.
+  Encode(Box<dyn std::error::Error + Send + Sync>),
+  /// A well formed frame the receiver does not accept at this point.
+  Protocol(String),
+  /// The sender may not do this.
+  Unauthorized(String),
+}
+
+impl ChatError {
+  /// Nothing arrived before the receive timeout, or a non-blocking call had nothing to do.
+  pub fn is_timeout(&self) -> bool {
+    matches!(self, ChatError::Transport(e) if e.kind() == std::io::ErrorKind::WouldBlock)
+  }
+
+  pub fn kind(&self) -> ErrorKind {
+    match self {
+      ChatError::Transport(_) => ErrorKind::Transport,
+      ChatError::Framing(_) => ErrorKind::Framing,
+      ChatError::Decode(_) => ErrorKind::Decode,
+      ChatError::Encode(_) => ErrorKind::Encode,
+      ChatError::Protocol(_) => ErrorKind::Protocol,
+      ChatError::Unauthorized(_) => ErrorKind::Unauthorized,
+    }
+  }
+
+  /// How to tell the peer about it, typed when it has the `TypedErrors` capability.
+  pub fn to_reply(&self, handshake: Option<&Handshake>) -> Reply {
+    let reason = match self {
+      ChatError::Transport(e) => e.to_string(),
+      ChatError::Decode(e) | ChatError::Encode(e) => e.to_string(),
+      ChatError::Framing(reason) | ChatError::Protocol(reason) | ChatError::Unauthorized(reason) => reason.clone(),
+    };
+    if handshake.is_some_and(|handshake| handshake.supports(Capability::TypedErrors)) {
+      Reply::Error { kind: self.kind(), reason }
+    } else {
+      Reply::Reason(reason)
+    }
+  }
+}
+
+impl std::fmt::Display for ChatError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      ChatError::Transport(e) => write!(f, "transport error: {}", e),
+      ChatError::Framing(reason) => write!(f, "malformed frame: {}", reason),
+      ChatError::Decode(e) => write!(f, "undecodable frame: {}", e),
+      ChatError::Encode(e) => write!(f, "cannot encode frame: {}", e),
+      ChatError::Protocol(reason) => write!(f, "protocol error: {}", reason),
+      ChatError::Unauthorized(reason) => write!(f, "unauthorized: {}", reason),
+    }
+  }
+}
+
+impl std::error::Error for ChatError {
+  fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+    match self {
+      ChatError::Transport(e) => Some(e),
+      ChatError::Decode(e) | ChatError::Encode(e) => Some(e.as_ref()),
+      _ => None,
+    }
+  }
+}
+
+impl From<zmq::Error> for ChatError {
+  fn from(e: zmq::Error) -> Self {
+    ChatError::Transport(e.into())
+  }
+}
+
+impl From<std::io::Error> for ChatError {
+  fn from(e: std::io::Error) -> Self {
+    ChatError::Transport(e)
+  }
+}
+
+impl From<serde_json::Error> for ChatError {
+  fn from(e: serde_json::Error) -> Self {
+    ChatError::Decode(Box::new(e))
+  }
+}
+
+impl From<rmp_serde::decode::Error> for ChatError {
+  fn from(e: rmp_serde::decode::Error) -> Self {
+    ChatError::Decode(Box::new(e))
+  }
+}
+
+/// `ChatError` as told to the peer in `Reply::Error`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+  Transport,
+  Framing,
+  Decode,
+  Encode,
+  Protocol,
+  Unauthorized,
+}
+
+impl std::fmt::Display for ErrorKind {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      ErrorKind::Transport => write!(f, "transport"),
+      ErrorKind::Framing => write!(f, "framing"),
+      ErrorKind::Decode => write!(f, "decode"),
+      ErrorKind::Encode => write!(f, "encode"),
+      ErrorKind::Protocol => write!(f, "protocol"),
+      ErrorKind::Unauthorized => write!(f, "unauthorized"),
+    }
+  }
+}
+
+/// Received frames are decoded in whichever codec they arrive in, `send_json` is `send_frame` in JSON.
 pub trait ZmqJsonServer {
-  fn recv_json<Protocols: for<'a> Deserialize<'a>>(&self, flags: Option<i32>) -> Result<(String, Protocols), Box<dyn std::error::Error>>;
-  fn send_json<Protocols: Serialize>(&self, client_id: &String, data: &Protocols, flags: Option<i32>) -> Result<(), Box<dyn std::error::Error>>;
+  /// The sender's identity and the payload, still encoded.
+  fn recv_frame(&self, flags: Option<i32>) -> Result<(String, Vec<u8>), ChatError>;
+  fn send_frame(&self, client_id: &str, data: &Protocols, codec: Codec, flags: Option<i32>) -> Result<(), ChatError>;
+  fn recv_json(&self, flags: Option<i32>) -> Result<(String, Protocols), ChatError> {
+    let (client_id, raw_msg) = self.recv_frame(flags)?;
+    Ok((client_id, decode_frame(&raw_msg)?))
+  }
+  fn send_json(&self, client_id: &str, data: &Protocols, flags: Option<i32>) -> Result<(), ChatError> {
+    self.send_frame(client_id, data, Codec::Json, flags)
+  }
 }
 
 pub trait ZmqJsonClient {
-  fn recv_json<Protocols: for<'a> Deserialize<'a>>(&self, flags: Option<i32>) -> Result<Protocols, Box<dyn std::error::Error>>; 
-  fn send_json<Protocols: Serialize>(&self, data: &Protocols, flags: Option<i32>) -> Result<(), Box<dyn std::error::Error>>;
+  fn recv_json(&self, flags: Option<i32>) -> Result<Protocols, ChatError>;
+  fn send_frame(&self, data: &Protocols, codec: Codec, flags: Option<i32>) -> Result<(), ChatError>;
+  fn send_json(&self, data: &Protocols, flags: Option<i32>) -> Result<(), ChatError> {
+    self.send_frame(data, Codec::Json, flags)
+  }
+}
+
+/// How `Protocols` envelopes are serialized on the wire. JSON until `register` agreed on another one.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Codec {
+  #[default]
+  #[serde(alias = "json")]
+  Json,
+  /// MessagePack with named fields, so `#[serde(default)]` fields and legacy decoders keep working.
+  #[serde(alias = "msgpack", alias = "messagepack")]
+  MessagePack,
+}
+
+impl Codec {
+  pub fn all() -> Vec<Codec> {
+    vec![Codec::Json, Codec::MessagePack]
+  }
+
+  pub fn encode(self, data: &Protocols) -> Result<Vec<u8>, ChatError> {
+    match self {
+      Codec::Json => serde_json::to_vec(data).map_err(|e| ChatError::Encode(Box::new(e))),
+      Codec::MessagePack => rmp_serde::to_vec_named(data).map_err(|e| ChatError::Encode(Box::new(e))),
+    }
+  }
+
+  pub fn decode(self, raw: &[u8]) -> Result<Protocols, ChatError> {
+    match self {
+      Codec::Json => Ok(serde_json::from_slice(raw)?),
+      Codec::MessagePack => Ok(rmp_serde::from_slice(raw)?),
+    }
+  }
+
+  /// A JSON envelope is an object and starts with `{`, a MessagePack one with a map marker, so frames tell their own codec.
+  pub fn detect(raw: &[u8]) -> Codec {
+    match raw.first() {
+      Some(0x80..=0x8f | 0xde | 0xdf) => Codec::MessagePack,
+      _ => Codec::Json,
+    }
+  }
+}
+
+impl std::str::FromStr for Codec {
+  type Err = String;
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s.to_ascii_lowercase().as_str() {
+      "json" => Ok(Codec::Json),
+      "msgpack" | "messagepack" => Ok(Codec::MessagePack),
+      _ => Err(format!("Unknown codec {}, expected json or msgpack", s)),
+    }
+  }
+}
+
+/// Every frame on the wire is a serialized `Protocols` envelope, whichever side sends it. Encoded in JSON,
+/// the codec every peer understands.
+pub fn encode_frame(data: &Protocols) -> Result<Vec<u8>, ChatError> {
+  Codec::Json.encode(data)
+}
+
+pub fn decode_frame(raw: &[u8]) -> Result<Protocols, ChatError> {
+  Codec::detect(raw).decode(raw)
+}
+
+/// Largest payload the server accepts. Up to twice as much still reaches the ROUTER and gets the sender
+/// a `ChatError::Framing` reply, libzmq drops the connection of anything larger.
+pub const MAX_FRAME_SIZE: usize = 1 << 20;
+/// zmq routing ids are at most 255 bytes.
+const MAX_IDENTITY_SIZE: usize = 255;
+
+/// The sender of a multipart message read from a ROUTER, when its first frame is a usable identity,
+/// so it can be told about a malformed remainder.
+pub fn frame_sender(frames: &[Vec<u8>]) -> Option<String> {
+  let identity = frames.first()?;
+  if identity.is_empty() || identity.len() > MAX_IDENTITY_SIZE {
+    return None;
+  }
+  String::from_utf8(identity.clone()).ok()
 }
 
+/// Split a multipart message read from a ROUTER into the sender's identity and its payload.
+/// Anything but exactly one non-empty payload frame after a UTF-8 identity is a `ChatError::Framing`.
+pub fn split_frames(mut frames: Vec<Vec<u8>>) -> Result<(String, Vec<u8>), ChatError> {
+  if frames.len() != 2 {
+    return Err(ChatError::Framing(format!("expected an identity and one payload frame, got {} frames", frames.len())));
+  }
+  let client_id = frame_sender(&frames)
+    .ok_or_else(|| ChatError::Framing(format!("identity of {} bytes is empty, too long or not UTF-8", frames[0].len())))?;
+  let payload = frames.pop().unwrap_or_default();
+  if payload.is_empty() {
+    return Err(ChatError::Framing(format!("empty payload from {}", client_id)));
+  }
+  if payload.len() > MAX_FRAME_SIZE {
+    return Err(ChatError::Framing(format!("payload of {} bytes from {} exceeds {}", payload.len(), client_id, MAX_FRAME_SIZE)));
+  }
+  Ok((client_id, payload))
+}
+
+impl ZmqJsonServer for zmq::Socket {
+  fn recv_frame(&self, flags: Option<i32>) -> Result<(String, Vec<u8>), ChatError> {
+    let raw_msgs = self.recv_multipart(flags.unwrap_or(0))?;
+    debug!("id: {:?}", raw_msgs.first());
+    split_frames(raw_msgs)
+  }
+  fn send_frame(&self, client_id: &str, data: &Protocols, codec: Codec, flags: Option<i32>) -> Result<(), ChatError> {
+    let frame = codec.encode(data)?;
+    let client_id_bytes = client_id.as_bytes();
+    self.send_multipart(&[client_id_bytes, &frame], flags.unwrap_or(0))?;
+    Ok(())
+  }
+}
+
+impl ZmqJsonClient for zmq::Socket {
+  fn recv_json(&self, flags: Option<i32>) -> Result<Protocols, ChatError> {
+    let raw_msg = self.recv_bytes(flags.unwrap_or(0))?;
+    let msg = decode_frame(&raw_msg)?;
+    Ok(msg)
+  }
+  fn send_frame(&self, data: &Protocols, codec: Codec, flags: Option<i32>) -> Result<(), ChatError> {
+    let frame = codec.encode(data)?;
+    self.send(&frame, flags.unwrap_or(0))?;
+    Ok(())
+  }
+}
+
+static PROMPT: Mutex<String> = Mutex::new(String::new());
+
 pub fn input(prompt: &str) -> String {
-  print!("{}", prompt);
-  std::io::stdout().flush().unwrap();
+  {
+    let mut current_prompt = PROMPT.lock().unwrap();
+    current_prompt.replace_range(.., prompt);
+    print!("{}", prompt);
+    std::io::stdout().flush().unwrap();
+  }
   let mut input = String::new();
-  std::io::stdin().read_line(&mut input).unwrap_or_else(|e|{error!("error occured in reading stdin: {}", e.to_string());0});
+  std::io::stdin().read_line(&mut input).unwrap_or_else(|e|{error!("error occured in reading stdin: {}", e);0});
+  PROMPT.lock().unwrap().clear();
   input.trim().to_string()
 }
 
-#[derive(Serialize, Deserialize, PartialEq)]
+/// Like `input`, but the answer is not echoed when stdin is a terminal.
+pub fn input_password(prompt: &str) -> String {
+  if !std::io::stdin().is_terminal() {
+    return input(prompt);
+  }
+  rpassword::prompt_password(prompt).unwrap_or_else(|e|{error!("error occured in reading password: {}", e);String::new()}).trim().to_string()
+}
+
+/// Print a line below the prompt `input` is currently waiting on, then prompt again.
+/// Characters already typed stay visible on the line above and are still part of the answer.
+pub fn print_notice(msg: &str) {
+  let current_prompt = PROMPT.lock().unwrap();
+  if current_prompt.is_empty() {
+    println!("{}", msg);
+  } else {
+    print!("\n{}\n{}", msg, current_prompt);
+  }
+  std::io::stdout().flush().unwrap();
+}
+
+/// Parse a shell duration such as `90s`, `10m`, `2h` or `7d`.
+pub fn parse_duration(text: &str) -> Result<chrono::Duration, String> {
+  let invalid = || format!("{} is not a duration like 30s, 10m, 2h or 7d", text);
+  let unit_at = text.len().checked_sub(1).filter(|at| text.is_char_boundary(*at)).ok_or_else(invalid)?;
+  let amount: i64 = text[..unit_at].parse().map_err(|_| invalid())?;
+  let seconds = match &text[unit_at..] {
+    "s" => 1,
+    "m" => 60,
+    "h" => 3600,
+    "d" => 24 * 3600,
+    _ => return Err(invalid()),
+  };
+  amount.checked_mul(seconds).filter(|seconds| *seconds > 0)
+    .and_then(chrono::Duration::try_seconds).ok_or_else(invalid)
+}
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Serialize, Deserialize, PartialEq, Clone, Copy)]
 pub enum MsgStatus {
   SUBMITTED,
   ACCEPTED,
   FAILED,
   REJECTED,
+  /// A direct message was handed to the target's connection.
+  DELIVERED,
+  /// The target's client showed a direct message to its user.
+  READ,
 }
 
 impl std::fmt::Display for MsgStatus {
@@ -36,29 +337,746 @@ impl std::fmt::Display for MsgStatus {
       MsgStatus::FAILED => write!(f, "FAILED"),
       MsgStatus::ACCEPTED => write!(f, "ACCEPTED"),
       MsgStatus::REJECTED => write!(f, "REJECTED"),
+      MsgStatus::DELIVERED => write!(f, "DELIVERED"),
+      MsgStatus::READ => write!(f, "READ"),
     }  
   }
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 pub enum ContactProtocol{
-  ServerControl{state: MsgStatus, command: String, cmd_args: Option<serde_json::Value>, time: DateTime<Utc>},
-  ClientControl{state: MsgStatus, command: String, cmd_args: Option<serde_json::Value>, time: DateTime<Utc>},
-  User2UserMsg{state: MsgStatus, target: String, content: MessageType, time: DateTime<Utc>},
+  ServerControl{
+    #[serde(default)] req_id: u64,
+    /// Token of the `Registered` reply, the server checks it on every request after `register`.
+    #[serde(default, skip_serializing_if = "Option::is_none")] session: Option<String>,
+    state: MsgStatus,
+    #[serde(deserialize_with = "compat::server_command")] command: ServerCommand,
+    time: DateTime<Utc>,
+  },
+  ClientControl{
+    #[serde(default)] req_id: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")] session: Option<String>,
+    state: MsgStatus,
+    #[serde(deserialize_with = "compat::client_command")] command: ClientCommand,
+    time: DateTime<Utc>,
+  },
+  User2UserMsg{
+    #[serde(default)] req_id: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")] session: Option<String>,
+    state: MsgStatus, target: String, content: MessageType, time: DateTime<Utc>,
+  },
+  RoomMsg{
+    req_id: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")] session: Option<String>,
+    state: MsgStatus, room: String, content: MessageType, time: DateTime<Utc>,
+  },
+  Response{req_id: u64, state: MsgStatus, reply: Reply, time: DateTime<Utc>},
+}
+
+impl ContactProtocol {
+  /// Id chosen by the client for a request and echoed back by the server in its response.
+  pub fn req_id(&self) -> u64 {
+    match self {
+      ContactProtocol::ServerControl { req_id, .. } => *req_id,
+      ContactProtocol::ClientControl { req_id, .. } => *req_id,
+      ContactProtocol::User2UserMsg { req_id, .. } => *req_id,
+      ContactProtocol::RoomMsg { req_id, .. } => *req_id,
+      ContactProtocol::Response { req_id, .. } => *req_id,
+    }
+  }
+  pub fn set_req_id(&mut self, id: u64) {
+    match self {
+      ContactProtocol::ServerControl { req_id, .. } => *req_id = id,
+      ContactProtocol::ClientControl { req_id, .. } => *req_id = id,
+      ContactProtocol::User2UserMsg { req_id, .. } => *req_id = id,
+      ContactProtocol::RoomMsg { req_id, .. } => *req_id = id,
+      ContactProtocol::Response { req_id, .. } => *req_id = id,
+    }
+  }
+
+  /// Session token a request was sent with, responses carry none.
+  pub fn session(&self) -> Option<&str> {
+    match self {
+      ContactProtocol::ServerControl { session, .. } | ContactProtocol::ClientControl { session, .. }
+        | ContactProtocol::User2UserMsg { session, .. } | ContactProtocol::RoomMsg { session, .. } => session.as_deref(),
+      ContactProtocol::Response { .. } => None,
+    }
+  }
+
+  pub fn set_session(&mut self, token: &str) {
+    match self {
+      ContactProtocol::ServerControl { session, .. } | ContactProtocol::ClientControl { session, .. }
+        | ContactProtocol::User2UserMsg { session, .. } | ContactProtocol::RoomMsg { session, .. } => *session = Some(token.to_string()),
+      ContactProtocol::Response { .. } => {},
+    }
+  }
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum ServerCommand {
+  /// Warn online clients, keep serving for `grace_secs`, then persist state and stop.
+  Shutdown{reason: Option<String>, grace_secs: u64},
+  AddUser{client_id: String, password: Credentials, admin: bool},
+  RemoveUser{client_id: String},
+  /// Disconnect an online client, it may register again.
+  Kick{client_id: String, reason: Option<String>},
+  /// Refuse the account's registers until `until`, forever when `None`, and kick it if online.
+  Ban{client_id: String, until: Option<DateTime<Utc>>, reason: Option<String>},
+  Unban{client_id: String},
+  Broadcast{content: String},
+  Info{client_id: String},
+}
+
+impl ServerCommand {
+  pub fn name(&self) -> &str {
+    match self {
+      ServerCommand::Shutdown { .. } => "shutdown",
+      ServerCommand::AddUser { .. } => "add_user",
+      ServerCommand::RemoveUser { .. } => "remove_user",
+      ServerCommand::Kick { .. } => "kick",
+      ServerCommand::Ban { .. } => "ban",
+      ServerCommand::Unban { .. } => "unban",
+      ServerCommand::Broadcast { .. } => "broadcast",
+      ServerCommand::Info { .. } => "info",
+    }
+  }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum ClientCommand {
+  Register{handshake: Handshake, #[serde(default)] credentials: Credentials},
+  ListClients,
+  Unregister,
+  /// Page through stored messages the requester sent or received, newest first.
+  History{peer: Option<String>, offset: usize, limit: usize},
+  CreateRoom{room: String},
+  JoinRoom{room: String},
+  LeaveRoom{room: String},
+  /// Only the room's creator or `root` may delete it.
+  DeleteRoom{room: String},
+  RoomMembers{room: String},
+  ListRooms,
+  /// Store the requester's end-to-end public key (Z85) for others to fetch.
+  PublishKey{public_key: String},
+  FetchKey{client_id: String},
+  /// Keep-alive sent periodically by clients with the `Heartbeat` capability.
+  Heartbeat,
+  /// Get `PresenceChanged` notifications whenever `client_id` comes online or goes away.
+  Watch{client_id: String},
+  Unwatch{client_id: String},
+  /// Change what watchers and listings show about the requester, `Offline` hides it while connected.
+  SetPresence{availability: Availability, status: Option<String>},
+  /// Read receipt for a direct message received with this id.
+  MarkRead{msg_id: u64},
+  /// Command served by a handler the server was extended with, `args` is up to that handler.
+  Custom{name: String, #[serde(default)] args: serde_json::Value},
+}
+
+impl ClientCommand {
+  /// Key of the handler serving this command, the legacy string command names where there was one.
+  pub fn name(&self) -> &str {
+    match self {
+      ClientCommand::Register { .. } => "register",
+      ClientCommand::ListClients => "get_clients",
+      ClientCommand::Unregister => "unregister",
+      ClientCommand::History { .. } => "history",
+      ClientCommand::CreateRoom { .. } => "create_room",
+      ClientCommand::JoinRoom { .. } => "join_room",
+      ClientCommand::LeaveRoom { .. } => "leave_room",
+      ClientCommand::DeleteRoom { .. } => "delete_room",
+      ClientCommand::RoomMembers { .. } => "room_members",
+      ClientCommand::ListRooms => "list_rooms",
+      ClientCommand::PublishKey { .. } => "publish_key",
+      ClientCommand::FetchKey { .. } => "fetch_key",
+      ClientCommand::Heartbeat => "heartbeat",
+      ClientCommand::Watch { .. } => "watch",
+      ClientCommand::Unwatch { .. } => "unwatch",
+      ClientCommand::SetPresence { .. } => "set_presence",
+      ClientCommand::MarkRead { .. } => "mark_read",
+      ClientCommand::Custom { name, .. } => name,
+    }
+  }
+}
+
+/// Version 2 requires every `register` to carry `Credentials::Password` or `Credentials::Token`. `Credentials::None`,
+/// which is all a v1 client could send, is always rejected: there are no anonymous logins.
+pub const PROTOCOL_VERSION: u32 = 2;
+/// Oldest version still served. Version 1 is the string command protocol used before `register` carried a handshake,
+/// its clients can't parse typed replies, so their `register` is rejected in the v1 frame shape (see `encode_legacy_rejection`).
+pub const MIN_PROTOCOL_VERSION: u32 = 2;
+
+/// Optional features a peer can handle, negotiated down to the set both sides support.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Capability {
+  /// Peer decodes pushed `NotifyProtocol` frames, clients before version 2 did not.
+  Notifications,
+  /// Peer decodes room notifications.
+  Rooms,
+  /// Peer can decrypt `MessageType::EncryptedMsg`.
+  Encryption,
+  /// Peer sends heartbeats and is evicted when it stops. Peers without it are only evicted after a much longer silence.
+  Heartbeat,
+  /// Peer decodes `Presence` listings and `StatusChanged` notifications.
+  Presence,
+  /// Peer decodes `Sent` replies and `Receipt` notifications, and may send `MarkRead`.
+  Receipts,
+  /// Peer decodes `Broadcast`, `Kicked` and `ServerShutdown` notifications.
+  ServerNotices,
+  /// Peer decodes `Reply::Error` instead of a plain `Reason` when a request fails for a `ChatError`.
+  TypedErrors,
+}
+
+impl Capability {
+  pub fn all() -> Vec<Capability> {
+    vec![Capability::Notifications, Capability::Rooms, Capability::Encryption, Capability::Heartbeat, Capability::Presence, Capability::Receipts, Capability::ServerNotices, Capability::TypedErrors]
+  }
+}
+
+/// Protocol range and capabilities a client announces in `register`, and the server answers with the agreed ones.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Handshake {
+  pub version: u32,
+  pub min_version: u32,
+  pub capabilities: Vec<Capability>,
+  /// Codecs in order of preference, a single one once agreed. Peers that don't send it only speak JSON.
+  #[serde(default = "Handshake::legacy_codecs")]
+  pub codecs: Vec<Codec>,
+}
+
+impl Handshake {
+  pub fn current() -> Handshake {
+    Handshake { version: PROTOCOL_VERSION, min_version: MIN_PROTOCOL_VERSION, capabilities: Capability::all(), codecs: Codec::all() }
+  }
+
+  /// `current` asking for `codec` first.
+  pub fn preferring(codec: Codec) -> Handshake {
+    let mut handshake = Handshake::current();
+    handshake.codecs.retain(|other| *other != codec);
+    handshake.codecs.insert(0, codec);
+    handshake
+  }
+
+  /// What a client registering without a handshake is assumed to speak.
+  pub fn legacy() -> Handshake {
+    Handshake { version: 1, min_version: 1, capabilities: Vec::new(), codecs: Handshake::legacy_codecs() }
+  }
+
+  fn legacy_codecs() -> Vec<Codec> {
+    vec![Codec::Json]
+  }
+
+  pub fn supports(&self, capability: Capability) -> bool {
+    self.capabilities.contains(&capability)
+  }
+
+  /// The agreed codec, or the preferred one before agreeing.
+  pub fn codec(&self) -> Codec {
+    self.codecs.first().copied().unwrap_or_default()
+  }
+
+  /// Agree on the highest version both sides speak and the capabilities both have, or explain why not.
+  pub fn negotiate(&self, peer: &Handshake) -> Result<Handshake, String> {
+    if peer.version < self.min_version || peer.min_version > self.version {
+      return Err(format!("Incompatible protocol version {} (min {}), server supports {}..={}",
+        peer.version, peer.min_version, self.min_version, self.version));
+    }
+    let capabilities = self.capabilities.iter().filter(|cap| peer.supports(**cap)).copied().collect();
+    let codec = peer.codecs.iter().find(|codec| self.codecs.contains(codec)).copied().unwrap_or_default();
+    Ok(Handshake { version: self.version.min(peer.version), min_version: self.min_version.max(peer.min_version), capabilities, codecs: vec![codec] })
+  }
+}
+
+/// Proof of identity presented in `register`: an account password, or a token from an earlier register.
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub enum Credentials {
+  /// What a `register` without credentials decodes to, the server always rejects it.
+  #[default]
+  None,
+  Password(String),
+  Token(String),
+}
+
+impl std::fmt::Debug for Credentials {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Credentials::None => write!(f, "None"),
+      Credentials::Password(_) => write!(f, "Password(..)"),
+      Credentials::Token(_) => write!(f, "Token(..)"),
+    }
+  }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Availability {
+  Online,
+  Away,
+  Busy,
+  Offline,
+}
+
+impl std::fmt::Display for Availability {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Availability::Online => write!(f, "online"),
+      Availability::Away => write!(f, "away"),
+      Availability::Busy => write!(f, "busy"),
+      Availability::Offline => write!(f, "offline"),
+    }
+  }
+}
+
+impl std::str::FromStr for Availability {
+  type Err = String;
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "online" => Ok(Availability::Online),
+      "away" => Ok(Availability::Away),
+      "busy" => Ok(Availability::Busy),
+      "offline" => Ok(Availability::Offline),
+      _ => Err(format!("Unknown availability {}, expected online, away, busy or offline", s)),
+    }
+  }
+}
+
+/// What others see about a client: availability, optional status text and when it was last active.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Presence {
+  pub availability: Availability,
+  pub status: Option<String>,
+  pub last_seen: DateTime<Utc>,
+}
+
+impl std::fmt::Display for Presence {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match &self.status {
+      Some(status) => write!(f, "{} ({})", self.availability, status),
+      None => write!(f, "{}", self.availability),
+    }
+  }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ClientPresence {
+  pub client_id: String,
+  pub presence: Presence,
+}
+
+/// What `ServerCommand::Info` tells about a client. Fields other than `client_id` are `None` when not known.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ClientInfo {
+  pub client_id: String,
+  pub admin: Option<bool>,
+  pub presence: Option<Presence>,
+  pub login_time: Option<DateTime<Utc>>,
+  pub handshake: Option<Handshake>,
+  pub ban: Option<String>,
+}
+
+impl std::fmt::Display for ClientInfo {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "{}", self.client_id)?;
+    if let Some(admin) = self.admin {
+      write!(f, "\n  role: {}", if admin {"admin"} else {"user"})?;
+    }
+    match &self.presence {
+      Some(presence) => write!(f, "\n  presence: {}, last seen {}", presence, presence.last_seen)?,
+      None => write!(f, "\n  presence: not seen since server start")?,
+    }
+    if let Some(login_time) = self.login_time {
+      write!(f, "\n  logged in: {}", login_time)?;
+    }
+    if let Some(handshake) = &self.handshake {
+      write!(f, "\n  protocol: v{} with {:?}", handshake.version, handshake.capabilities)?;
+    }
+    if let Some(ban) = &self.ban {
+      write!(f, "\n  {}", ban)?;
+    }
+    Ok(())
+  }
+}
+
+/// Payload of a `ContactProtocol::Response`, typed per command.
+#[derive(Serialize, Deserialize, Clone)]
+pub enum Reply {
+  Done,
+  /// Target is offline, the message waits until it registers again.
+  Queued,
+  /// Direct message accepted under `msg_id`, answered instead of `Done` or `Queued` to peers with the `Receipts` capability.
+  Sent{msg_id: u64, queued: bool},
+  /// Register accepted with the agreed handshake and a session token to present on the next register.
+  Registered{handshake: Handshake, token: String},
+  Clients(Vec<String>),
+  /// `ListClients` answer for peers with the `Presence` capability, offline clients seen since startup included.
+  Presences(Vec<ClientPresence>),
+  History{messages: Vec<HistoryEntry>, total: usize},
+  Rooms(Vec<String>),
+  Members{room: String, owner: String, members: Vec<String>},
+  PublicKey{client_id: String, public_key: String},
+  ClientInfo(ClientInfo),
+  /// Answer of a `Custom` command's handler.
+  Custom(serde_json::Value),
+  /// A failed request's `ChatError`, for peers with the `TypedErrors` capability.
+  Error{kind: ErrorKind, reason: String},
+  Reason(String),
+}
+
+impl std::fmt::Display for Reply {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Reply::Done => write!(f, "done"),
+      Reply::Queued | Reply::Sent { queued: true, .. } => write!(f, "queued until the target is back online"),
+      Reply::Sent { msg_id, .. } => write!(f, "sent as #{}", msg_id),
+      Reply::Registered { handshake, .. } => write!(f, "protocol v{} in {:?} with {:?}", handshake.version, handshake.codec(), handshake.capabilities),
+      Reply::Clients(clients) => write!(f, "{}", clients.join(", ")),
+      Reply::Presences(clients) => {
+        let entries: Vec<String> = clients.iter().map(|client| format!("{} {}", client.client_id, client.presence)).collect();
+        write!(f, "{}", entries.join(", "))
+      },
+      Reply::History { messages, total } => write!(f, "{} of {} messages", messages.len(), total),
+      Reply::Rooms(rooms) => write!(f, "{}", rooms.join(", ")),
+      Reply::Members { room, owner, members } => write!(f, "{} (owner {}): {}", room, owner, members.join(", ")),
+      Reply::PublicKey { client_id, public_key } => write!(f, "{}: {}", client_id, public_key),
+      Reply::ClientInfo(info) => write!(f, "{}", info),
+      Reply::Custom(value) => write!(f, "{}", value),
+      Reply::Error { kind, reason } => write!(f, "{} error: {}", kind, reason),
+      Reply::Reason(reason) => write!(f, "{}", reason),
+    }
+  }
+}
+
+/// A routed `User2UserMsg` as recorded by the server's message store.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct HistoryEntry {
+  #[serde(default)]
+  pub msg_id: u64,
+  pub sender: String,
+  pub target: String,
+  pub content: MessageType,
+  pub time: DateTime<Utc>,
+}
+
+/// Answer a protocol v1 `register` with `reason` as `REJECTED`, in the only frame v1 clients parse.
+pub fn encode_legacy_rejection(reason: &str) -> Result<Vec<u8>, ChatError> {
+  let rejection = compat::LegacyProtocols::CPType(compat::LegacyContactProtocol::ClientControl {
+    state: MsgStatus::REJECTED, command: reason.to_string(), cmd_args: None, time: Utc::now() });
+  Ok(serde_json::to_vec(&rejection)?)
+}
+
+/// Decoders accepting the old `command: "register"` string form next to the typed enums.
+/// The old `cmd_args` field is ignored, none of the old commands used it.
+mod compat {
+  use chrono::{DateTime, Utc};
+  use serde::{Deserialize, Deserializer, Serialize};
+  use super::{ClientCommand, Credentials, Handshake, MsgStatus, ServerCommand};
+
+  /// `Protocols` as protocol v1 clients parse it.
+  #[derive(Serialize, Deserialize)]
+  pub enum LegacyProtocols {
+    CPType(LegacyContactProtocol),
+  }
+
+  /// The v1 reply, `command` carries the reason when `state` is not `ACCEPTED`.
+  #[derive(Serialize, Deserialize)]
+  pub enum LegacyContactProtocol {
+    ClientControl{state: MsgStatus, command: String, cmd_args: Option<serde_json::Value>, time: DateTime<Utc>},
+  }
+
+  #[derive(Deserialize)]
+  #[serde(rename_all = "snake_case")]
+  enum LegacyClientCommand {
+    #[serde(alias = "Register")]
+    Register,
+    GetClients,
+    Unregister,
+  }
+
+  #[derive(Deserialize)]
+  #[serde(rename_all = "snake_case")]
+  enum LegacyServerCommand {
+    Shutdown,
+  }
+
+  #[derive(Deserialize)]
+  #[serde(untagged)]
+  enum Either<T, L> {
+    Typed(T),
+    Legacy(L),
+  }
+
+  pub fn client_command<'de, D: Deserializer<'de>>(deserializer: D) -> Result<ClientCommand, D::Error> {
+    Ok(match Either::<ClientCommand, LegacyClientCommand>::deserialize(deserializer)? {
+      Either::Typed(command) => command,
+      Either::Legacy(LegacyClientCommand::Register) => ClientCommand::Register { handshake: Handshake::legacy(), credentials: Credentials::None },
+      Either::Legacy(LegacyClientCommand::GetClients) => ClientCommand::ListClients,
+      Either::Legacy(LegacyClientCommand::Unregister) => ClientCommand::Unregister,
+    })
+  }
+
+  pub fn server_command<'de, D: Deserializer<'de>>(deserializer: D) -> Result<ServerCommand, D::Error> {
+    Ok(match Either::<ServerCommand, LegacyServerCommand>::deserialize(deserializer)? {
+      Either::Typed(command) => command,
+      Either::Legacy(LegacyServerCommand::Shutdown) => ServerCommand::Shutdown { reason: None, grace_secs: 0 },
+    })
+  }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
 pub enum NotifyProtocol {
-  MsgFromUser{sender: String, content: MessageType},
+  MsgFromUser{#[serde(default)] msg_id: u64, sender: String, content: MessageType, time: DateTime<Utc>},
+  MsgFromRoom{room: String, sender: String, content: MessageType, time: DateTime<Utc>},
+  RoomDeleted{room: String, by: String},
+  /// A watched client registered (`online`), unregistered or was evicted.
+  PresenceChanged{client_id: String, online: bool, time: DateTime<Utc>},
+  /// Any change of a watched client's presence, sent instead of `PresenceChanged` to peers with the `Presence` capability.
+  StatusChanged{client_id: String, presence: Presence},
+  /// Progress of a direct message the receiver sent, `from` is the message's target.
+  Receipt{msg_id: u64, from: String, state: MsgStatus, time: DateTime<Utc>},
+  /// Announcement from an admin to every online client.
+  Broadcast{sender: String, content: String, time: DateTime<Utc>},
+  /// The server dropped this client's registration, it should not register again on its own.
+  Kicked{reason: String},
+  /// The server stops in `grace_secs`, clients should register again once it is back.
+  ServerShutdown{reason: Option<String>, grace_secs: u64, time: DateTime<Utc>},
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 pub enum MessageType {
   TextMsg{content: String},
+  /// Text sealed for the target with its published key, the server only routes the ciphertext.
+  /// `sender_key` is the sender's Z85 public key the recipient needs to open it.
+  /// The bytes go out as MessagePack binaries, JSON still writes them as arrays of numbers.
+  EncryptedMsg{sender_key: String, #[serde(with = "serde_bytes")] nonce: Vec<u8>, #[serde(with = "serde_bytes")] ciphertext: Vec<u8>},
+}
+
+impl std::fmt::Display for MessageType {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      MessageType::TextMsg { content } => write!(f, "{}", content),
+      MessageType::EncryptedMsg { .. } => write!(f, "<encrypted>"),
+    }
+  }
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 pub enum Protocols {
   CPType(ContactProtocol),
   NPType(NotifyProtocol),
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn connected_pair(endpoint: &str) -> (zmq::Context, zmq::Socket, zmq::Socket) {
+    let ctx = zmq::Context::new();
+    let router = ctx.socket(zmq::ROUTER).unwrap();
+    router.bind(endpoint).unwrap();
+    let dealer = ctx.socket(zmq::DEALER).unwrap();
+    dealer.set_identity(b"alice").unwrap();
+    dealer.set_rcvtimeo(1000).unwrap();
+    dealer.connect(endpoint).unwrap();
+    router.set_rcvtimeo(1000).unwrap();
+    (ctx, router, dealer)
+  }
+
+  #[test]
+  fn notify_frame_decodes_on_client() {
+    let (_ctx, router, dealer) = connected_pair("inproc://notify_frame");
+    let hello = Protocols::CPType(ContactProtocol::ClientControl { req_id: 1, session: None, state: MsgStatus::SUBMITTED, command: ClientCommand::Register { handshake: Handshake::current(), credentials: Credentials::None }, time: Utc::now() });
+    ZmqJsonClient::send_json(&dealer, &hello, None).unwrap();
+    let (client_id, _) = ZmqJsonServer::recv_json(&router, None).unwrap();
+    assert_eq!(client_id, "alice");
+
+    let notification = Protocols::NPType(NotifyProtocol::MsgFromUser { msg_id: 7,
+      sender: "bob".to_string(), content: MessageType::TextMsg { content: "hi".to_string() }, time: Utc::now() });
+    ZmqJsonServer::send_json(&router, &client_id, &notification, None).unwrap();
+    match ZmqJsonClient::recv_json(&dealer, None).unwrap() {
+      Protocols::NPType(NotifyProtocol::MsgFromUser { msg_id, sender, content, .. }) => {
+        assert_eq!(msg_id, 7);
+        assert_eq!(sender, "bob");
+        assert_eq!(content.to_string(), "hi");
+      },
+      _ => panic!("expected MsgFromUser notification"),
+    }
+  }
+
+  #[test]
+  fn response_frame_round_trip() {
+    let response = Protocols::CPType(ContactProtocol::Response {
+      req_id: 42, state: MsgStatus::FAILED, reply: Reply::Reason("No such target".to_string()), time: Utc::now() });
+    match decode_frame(&encode_frame(&response).unwrap()).unwrap() {
+      Protocols::CPType(ContactProtocol::Response { req_id, state, reply: Reply::Reason(reason), .. }) => {
+        assert_eq!(req_id, 42);
+        assert!(state == MsgStatus::FAILED);
+        assert_eq!(reason, "No such target");
+      },
+      _ => panic!("expected Response"),
+    }
+  }
+
+  #[test]
+  fn message_pack_frames_decode() {
+    let hello = Protocols::CPType(ContactProtocol::ClientControl { req_id: 1, session: None, state: MsgStatus::SUBMITTED,
+      command: ClientCommand::Register { handshake: Handshake::preferring(Codec::MessagePack), credentials: Credentials::Token("t".to_string()) }, time: Utc::now() });
+    let raw = Codec::MessagePack.encode(&hello).unwrap();
+    assert_eq!(Codec::detect(&raw), Codec::MessagePack);
+    assert!(raw.len() < encode_frame(&hello).unwrap().len());
+    match decode_frame(&raw).unwrap() {
+      Protocols::CPType(ContactProtocol::ClientControl { req_id, command: ClientCommand::Register { handshake, credentials: Credentials::Token(token) }, .. }) => {
+        assert_eq!(req_id, 1);
+        assert_eq!(token, "t");
+        assert_eq!(handshake.codec(), Codec::MessagePack);
+      },
+      _ => panic!("expected Register"),
+    }
+
+    let (_ctx, router, dealer) = connected_pair("inproc://message_pack");
+    ZmqJsonClient::send_frame(&dealer, &hello, Codec::MessagePack, None).unwrap();
+    let (client_id, _) = ZmqJsonServer::recv_json(&router, None).unwrap();
+    let custom = Protocols::CPType(ContactProtocol::Response { req_id: 1, state: MsgStatus::ACCEPTED,
+      reply: Reply::Custom(serde_json::json!({"echo": [1, "two", null]})), time: Utc::now() });
+    ZmqJsonServer::send_frame(&router, &client_id, &custom, Codec::MessagePack, None).unwrap();
+    match ZmqJsonClient::recv_json(&dealer, None).unwrap() {
+      Protocols::CPType(ContactProtocol::Response { reply: Reply::Custom(value), .. }) => assert_eq!(value, serde_json::json!({"echo": [1, "two", null]})),
+      _ => panic!("expected Custom response"),
+    }
+  }
+
+  #[test]
+  fn encrypted_payloads_are_binary() {
+    let ciphertext: Vec<u8> = (0..=255).collect();
+    let sealed = Protocols::NPType(NotifyProtocol::MsgFromUser { msg_id: 3, sender: "alice".to_string(),
+      content: MessageType::EncryptedMsg { sender_key: "k".repeat(40), nonce: vec![7; 24], ciphertext: ciphertext.clone() }, time: Utc::now() });
+    let raw = Codec::MessagePack.encode(&sealed).unwrap();
+    assert!(raw.windows(ciphertext.len()).any(|window| window == ciphertext.as_slice()));
+    // JSON keeps the array of numbers older peers expect
+    let json = encode_frame(&sealed).unwrap();
+    assert!(String::from_utf8(json.clone()).unwrap().contains(r#""nonce":[7,7,"#));
+    // Arrays still decode, as MessagePack peers sent them before
+    let as_arrays = rmp_serde::to_vec_named(&serde_json::from_slice::<serde_json::Value>(&json).unwrap()).unwrap();
+    assert!(as_arrays.len() > raw.len());
+    for frame in [raw, json, as_arrays] {
+      match decode_frame(&frame).unwrap() {
+        Protocols::NPType(NotifyProtocol::MsgFromUser { content: MessageType::EncryptedMsg { nonce, ciphertext: decoded, .. }, .. }) => {
+          assert_eq!(nonce, vec![7; 24]);
+          assert_eq!(decoded, ciphertext);
+        },
+        _ => panic!("expected EncryptedMsg"),
+      }
+    }
+  }
+
+  #[test]
+  fn legacy_string_commands_decode() {
+    let legacy = br#"{"CPType":{"ClientControl":{"state":"SUBMITTED","command":"get_clients","cmd_args":null,"time":"2024-01-01T00:00:00Z"}}}"#;
+    match decode_frame(legacy).unwrap() {
+      Protocols::CPType(ContactProtocol::ClientControl { req_id, command: ClientCommand::ListClients, .. }) => assert_eq!(req_id, 0),
+      _ => panic!("expected ListClients"),
+    }
+    let legacy = br#"{"CPType":{"ServerControl":{"state":"SUBMITTED","command":"shutdown","cmd_args":null,"time":"2024-01-01T00:00:00Z"}}}"#;
+    assert!(matches!(decode_frame(legacy).unwrap(), Protocols::CPType(ContactProtocol::ServerControl { command: ServerCommand::Shutdown { reason: None, grace_secs: 0 }, .. })));
+    let legacy = br#"{"CPType":{"ClientControl":{"state":"SUBMITTED","command":"register","cmd_args":null,"time":"2024-01-01T00:00:00Z"}}}"#;
+    match decode_frame(legacy).unwrap() {
+      Protocols::CPType(ContactProtocol::ClientControl { command: ClientCommand::Register { handshake, credentials: Credentials::None }, .. }) => assert_eq!(handshake.version, 1),
+      _ => panic!("expected Register"),
+    }
+    let unknown = br#"{"CPType":{"ClientControl":{"state":"SUBMITTED","command":"reboot","cmd_args":null,"time":"2024-01-01T00:00:00Z"}}}"#;
+    assert!(decode_frame(unknown).is_err());
+  }
+
+  #[test]
+  fn negotiate_versions() {
+    let server = Handshake::current();
+    assert!(server.negotiate(&Handshake::legacy()).is_err());
+    let agreed = server.negotiate(&Handshake::current()).unwrap();
+    assert_eq!(agreed.version, PROTOCOL_VERSION);
+    assert!(agreed.supports(Capability::Notifications));
+    assert_eq!(agreed.codec(), Codec::Json);
+    assert_eq!(server.negotiate(&Handshake::preferring(Codec::MessagePack)).unwrap().codecs, vec![Codec::MessagePack]);
+    let future = Handshake { version: PROTOCOL_VERSION + 5, min_version: PROTOCOL_VERSION + 1, capabilities: Vec::new(), codecs: Codec::all() };
+    assert!(server.negotiate(&future).is_err());
+  }
+
+  #[test]
+  fn shell_durations() {
+    assert_eq!(parse_duration("90s").unwrap(), chrono::Duration::seconds(90));
+    assert_eq!(parse_duration("2h").unwrap(), chrono::Duration::hours(2));
+    assert_eq!(parse_duration("7d").unwrap(), chrono::Duration::days(7));
+    for bad in ["", "d", "10", "5w", "-3m", "0s", "spam"] {
+      assert!(parse_duration(bad).is_err(), "{} accepted", bad);
+    }
+  }
+
+  #[test]
+  fn bare_notify_is_not_a_frame() {
+    let bare = NotifyProtocol::MsgFromUser { msg_id: 0, sender: "bob".to_string(), content: MessageType::TextMsg { content: "hi".to_string() }, time: Utc::now() };
+    assert!(decode_frame(&serde_json::to_vec(&bare).unwrap()).is_err());
+  }
+
+  #[test]
+  fn errors_keep_their_kind() {
+    let (_ctx, _router, dealer) = connected_pair("inproc://errors_kind");
+    dealer.set_rcvtimeo(10).unwrap();
+    let e = ZmqJsonClient::recv_json(&dealer, None).err().unwrap();
+    assert!(e.is_timeout());
+    assert_eq!(e.kind(), ErrorKind::Transport);
+
+    let e = decode_frame(b"{not json").err().unwrap();
+    assert!(!e.is_timeout());
+    assert_eq!(e.kind(), ErrorKind::Decode);
+    assert!(matches!(e.to_reply(Some(&Handshake::current())), Reply::Error { kind: ErrorKind::Decode, .. }));
+    assert!(matches!(e.to_reply(Some(&Handshake::legacy())), Reply::Reason(_)));
+    assert!(matches!(e.to_reply(None), Reply::Reason(_)));
+  }
+
+  #[test]
+  fn malformed_messages_are_framing_errors() {
+    let (_ctx, router, dealer) = connected_pair("inproc://malformed");
+    dealer.send(&b""[..], 0).unwrap();
+    assert!(matches!(router.recv_frame(None), Err(ChatError::Framing(_))));
+    dealer.send_multipart(&[&b"a"[..], &b"b"[..]], 0).unwrap();
+    assert!(matches!(router.recv_frame(None), Err(ChatError::Framing(_))));
+    dealer.send(&b"{}"[..], 0).unwrap();
+    assert!(matches!(ZmqJsonServer::recv_json(&router, None), Err(ChatError::Decode(_))));
+
+    assert!(matches!(split_frames(Vec::new()), Err(ChatError::Framing(_))));
+    assert!(matches!(split_frames(vec![b"alice".to_vec()]), Err(ChatError::Framing(_))));
+    assert!(matches!(split_frames(vec![vec![0xff, 0xfe], b"{}".to_vec()]), Err(ChatError::Framing(_))));
+    assert!(matches!(split_frames(vec![b"alice".to_vec(), vec![b' '; MAX_FRAME_SIZE + 1]]), Err(ChatError::Framing(_))));
+    assert_eq!(frame_sender(&[b"alice".to_vec(), b"a".to_vec(), b"b".to_vec()]).as_deref(), Some("alice"));
+    assert_eq!(frame_sender(&[b"alice".to_vec(), vec![b' '; MAX_FRAME_SIZE + 1]]).as_deref(), Some("alice"));
+    assert!(frame_sender(&[vec![0xff, 0xfe], b"{}".to_vec()]).is_none());
+    assert!(frame_sender(&[vec![b'a'; 256], b"{}".to_vec()]).is_none());
+    assert!(frame_sender(&[]).is_none());
+    let (client_id, payload) = split_frames(vec![b"alice".to_vec(), b"{}".to_vec()]).unwrap();
+    assert_eq!((client_id.as_str(), payload.as_slice()), ("alice", &b"{}"[..]));
+  }
+
+  proptest::proptest! {
+    #[test]
+    fn arbitrary_frames_never_panic(frames in proptest::collection::vec(proptest::collection::vec(proptest::num::u8::ANY, 0..64), 0..5)) {
+      let shape_ok = frames.len() == 2 && !frames[1].is_empty() && !frames[0].is_empty() && std::str::from_utf8(&frames[0]).is_ok();
+      match split_frames(frames.clone()) {
+        Ok((client_id, payload)) => {
+          proptest::prop_assert!(shape_ok);
+          proptest::prop_assert_eq!(client_id.as_bytes(), frames[0].as_slice());
+          let _ = decode_frame(&payload);
+        },
+        Err(e) => {
+          proptest::prop_assert!(!shape_ok);
+          proptest::prop_assert_eq!(e.kind(), ErrorKind::Framing);
+        }
+      }
+    }
+
+    #[test]
+    fn decoder_survives_mangled_frames(response in "[ -~]{0,40}", cut in 0usize..200, flip in proptest::num::u8::ANY, msgpack in proptest::bool::ANY) {
+      let frame = Protocols::CPType(ContactProtocol::Response { req_id: 1, state: MsgStatus::ACCEPTED, reply: Reply::Reason(response), time: Utc::now() });
+      let codec = if msgpack {Codec::MessagePack} else {Codec::Json};
+      let mut raw = codec.encode(&frame).unwrap();
+      raw.truncate(cut.min(raw.len()));
+      if let Some(byte) = raw.last_mut() {*byte ^= flip;}
+      let _ = decode_frame(&raw);
+    }
+  }
+}